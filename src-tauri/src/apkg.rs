@@ -0,0 +1,295 @@
+use anyhow::{bail, Context, Result};
+use if_chain::if_chain;
+use rusqlite::{Connection, OpenFlags};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::cmp::Reverse;
+use std::path::Path;
+use tokio::task;
+use zip::ZipArchive;
+
+use crate::deck::{self, AudioState, Deck, DeckError, MediaNaming, Note, AUDIO_SUFFIX, NOTE_FIELDS};
+use crate::ext::string::StringExt;
+use crate::file::{card_back_path, card_front_path, css_path, deck_path, media_path, DEFAULT_CARD};
+
+static WORD_FIELDS: [&str; 3] = ["Word", "Expression", "Front"];
+static READING_FIELDS: [&str; 2] = ["Reading", "Furigana"];
+static DEFINITION_FIELDS: [&str; 3] = ["Definition", "Meaning", "Back"];
+static TRANSCRIPTION_FIELDS: [&str; 2] = ["Transcription", "Sentence"];
+static PRONUNCIATION_FIELDS: [&str; 2] = ["Pronunciation", "Audio"];
+static MEDIA_FIELDS: [&str; 3] = ["Media", "Image", "Picture"];
+
+#[derive(Deserialize)]
+struct AnkiModel {
+    flds: Vec<AnkiField>,
+    tmpls: Vec<AnkiTemplate>,
+    #[serde(default)]
+    css: String,
+}
+
+#[derive(Deserialize)]
+struct AnkiField {
+    name: String,
+    ord: usize,
+}
+
+#[derive(Deserialize)]
+struct AnkiTemplate {
+    #[serde(default)]
+    name: String,
+    qfmt: String,
+    afmt: String,
+}
+
+#[derive(Deserialize)]
+struct AnkiDeck {
+    name: String,
+    #[serde(default)]
+    desc: String,
+}
+
+struct AnkiNote {
+    guid: String,
+    model_id: i64,
+    fields: String,
+    deck_id: Option<i64>,
+    tags: String,
+}
+
+/// Creates a deck directory (`deck.json`, templates and `media/`) from an Anki package.
+///
+/// Fields are mapped by name, so packages written by this tool round-trip and packages
+/// from other tools import as long as their note type uses common field names.
+pub async fn import_apkg(apkg: &str, dir: &str) -> Result<Deck> {
+    if Path::new(&deck_path(dir)).exists() {
+        bail!(DeckError::FileExistsError(deck_path(dir)));
+    }
+
+    let (apkg, dir_owned) = (apkg.to_string(), dir.to_string());
+
+    let deck = task::spawn_blocking(move || read_apkg(&apkg, &dir_owned)).await
+        .with_context(|| "Error while importing the package")??;
+
+    deck.write(&deck_path(dir)).await?;
+    Ok(deck)
+}
+
+fn read_apkg(apkg: &str, dir: &str) -> Result<Deck> {
+    let file = File::open(apkg).with_context(|| DeckError::NotFoundError(apkg.to_string()))?;
+    let mut archive = ZipArchive::new(file).with_context(|| format!("{} is not an Anki package", apkg))?;
+
+    // Anki 2.1.50+ writes a compressed `collection.anki21b` next to a `collection.anki2`
+    // holding a single note that asks to update Anki
+    let collection_name = ["collection.anki21", "collection.anki21b", "collection.anki2"]
+        .iter()
+        .find(|name| archive.by_name(name).is_ok())
+        .filter(|name| **name != "collection.anki21b")
+        .with_context(|| format!("{} has no collection, packages from Anki 2.1.50+ must be exported with legacy support", apkg))?;
+
+    fs::create_dir_all(media_path(dir))?;
+
+    let collection_path = format!("{}/.collection.tmp", dir);
+    extract(&mut archive, collection_name, &collection_path)?;
+    let collection = read_collection(&collection_path);
+    fs::remove_file(&collection_path)?;
+    let (models, decks, anki_notes) = collection?;
+
+    let media_map: HashMap<String, String> = match archive.by_name("media") {
+        Ok(entry) => serde_json::from_reader(entry).with_context(|| "Invalid media map")?,
+        Err(_) => HashMap::new(),
+    };
+    let media_entries: HashMap<String, String> = media_map.into_iter().map(|(k, v)| (v, k)).collect();
+
+    let deck_id = most_common(anki_notes.iter().filter_map(|it| it.deck_id)).unwrap_or(1);
+    let anki_deck = decks.get(&deck_id.to_string());
+
+    let mut deck = Deck {
+        id: deck_id as usize,
+        name: anki_deck.map(|it| it.name.clone()).unwrap_or_else(|| "Imported".to_string()),
+        description: anki_deck.map(|it| it.desc.clone()).filter(|it| !it.is_empty()),
+        media_naming: MediaNaming::Separated,
+        ..Default::default()
+    };
+
+    let media_dir = media_path(dir);
+
+    let main_model = most_common(anki_notes.iter().map(|it| it.model_id))
+        .and_then(|id| models.get(&id.to_string()).map(|model| (id, model)));
+
+    // Packages written by this tool keep updating their note type once imported back,
+    // older ones having fewer built-in fields and newer ones the custom fields of the deck
+    if let Some((model_id, model)) = main_model {
+        let mut fields: Vec<&AnkiField> = model.flds.iter().collect();
+        fields.sort_by_key(|it| it.ord);
+        let len = fields.len().min(NOTE_FIELDS.len());
+        let ours = len > 0 && fields.iter().zip(NOTE_FIELDS.iter()).all(|(field, name)| field.name == *name);
+
+        if ours {
+            deck.model_id = Some(model_id as usize);
+            deck.fields = fields.iter().skip(len).map(|it| it.name.clone()).collect();
+        }
+    }
+
+    for (index, anki_note) in anki_notes.iter().enumerate() {
+        let model = models.get(&anki_note.model_id.to_string())
+            .with_context(|| format!("Note type {} not found", anki_note.model_id))?;
+        let values: Vec<&str> = anki_note.fields.split('\x1f').collect();
+        let field = |names: &[&str]| field_value(model, &values, names);
+
+        let id = index as u32 + 1;
+        let key = deck.media_key(id);
+        let word = field(&WORD_FIELDS).or_else(|| values.get(0).map(|it| it.to_string())).unwrap_or_default();
+        let reading = field(&READING_FIELDS)
+            .filter(|it| !it.is_empty())
+            .map(|it| if it.contains_ruby() { it.ruby_reading() } else { it });
+        let definition = field(&DEFINITION_FIELDS)
+            .or_else(|| values.get(1).map(|it| it.to_string()))
+            .unwrap_or_default();
+
+        let mut note = Note {
+            id: Some(id),
+            word,
+            reading,
+            definition,
+            transcription: field(&TRANSCRIPTION_FIELDS).unwrap_or_default(),
+            guid: Some(anki_note.guid.clone()),
+            tags: anki_note.tags.split_whitespace().map(|it| it.to_string()).collect(),
+            ..Default::default()
+        };
+
+        if deck.model_id == Some(anki_note.model_id as usize) {
+            for name in deck.fields.iter() {
+                if let Some(value) = field(&[name.as_str()]).filter(|it| !it.is_empty()) {
+                    note.fields.insert(name.clone(), value);
+                }
+            }
+        }
+
+        let pronunciation = field(&PRONUNCIATION_FIELDS).and_then(|it| media_refs(&it).into_iter().next());
+
+        if let Some(entry) = pronunciation.and_then(|it| media_entries.get(&it)) {
+            extract(&mut archive, entry, &format!("{}/{}{}", media_dir, key, AUDIO_SUFFIX))?;
+            note.audio_state = Some(AudioState::OK.to_string());
+        }
+
+        let media = field(&MEDIA_FIELDS).and_then(|it| media_refs(&it).into_iter().next());
+
+        if_chain! {
+            if let Some(media) = media;
+            if let Some(entry) = media_entries.get(&media);
+            if let Some(ext) = Path::new(&media).extension().and_then(|it| it.to_str());
+            if deck::is_valid_extension(&ext.to_lowercase());
+            then {
+                extract(&mut archive, entry, &format!("{}/{}.{}", media_dir, key, ext.to_lowercase()))?;
+            }
+        }
+
+        deck.notes.push(note);
+    }
+
+    // The first card type becomes front.html/back.html, the others <name>.front.html/<name>.back.html
+    if let Some((_, model)) = main_model {
+        for (i, tmpl) in model.tmpls.iter().enumerate() {
+            let card = if i == 0 { DEFAULT_CARD.to_string() } else { card_file_name(&tmpl.name, i) };
+            write_if_missing(&card_front_path(dir, &card), &tmpl.qfmt)?;
+            write_if_missing(&card_back_path(dir, &card), &tmpl.afmt)?;
+            deck.cards.push(card);
+        }
+        write_if_missing(&css_path(dir), &model.css)?;
+    }
+
+    deck.model_id.get_or_insert(deck.id);
+
+    Ok(deck)
+}
+
+fn read_collection(path: &str) -> Result<(HashMap<String, AnkiModel>, HashMap<String, AnkiDeck>, Vec<AnkiNote>)> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let (models, decks): (String, String) = conn.query_row(
+        "SELECT models, decks FROM col",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    let mut stmt = conn.prepare(
+        "SELECT n.guid, n.mid, n.flds, (SELECT c.did FROM cards c WHERE c.nid = n.id LIMIT 1), n.tags
+         FROM notes n ORDER BY n.id",
+    )?;
+
+    let notes = stmt
+        .query_map([], |row| Ok(AnkiNote {
+            guid: row.get(0)?,
+            model_id: row.get(1)?,
+            fields: row.get(2)?,
+            deck_id: row.get(3)?,
+            tags: row.get(4)?,
+        }))?
+        .collect::<rusqlite::Result<Vec<AnkiNote>>>()?;
+
+    Ok((
+        serde_json::from_str(&models).with_context(|| "Invalid note types")?,
+        serde_json::from_str(&decks).with_context(|| "Invalid decks")?,
+        notes,
+    ))
+}
+
+fn field_value(model: &AnkiModel, values: &[&str], names: &[&str]) -> Option<String> {
+    names.iter()
+        .find_map(|name| model.flds.iter().find(|it| it.name.eq_ignore_ascii_case(name)))
+        .and_then(|field| values.get(field.ord))
+        .map(|it| it.to_string())
+}
+
+/// File names referenced by `[sound:...]` tags and `<img src="...">` elements
+fn media_refs(value: &str) -> Vec<String> {
+    let mut refs = Vec::new();
+
+    for (start, end) in [("[sound:", "]"), ("src=\"", "\"")] {
+        let mut rest = value;
+        while let Some(i) = rest.find(start) {
+            rest = &rest[i + start.len()..];
+            if let Some(j) = rest.find(end) {
+                refs.push(rest[..j].to_string());
+                rest = &rest[j..];
+            }
+        }
+    }
+
+    refs
+}
+
+/// Most frequent item, the lowest one on ties so that imports do not depend on hash order
+fn most_common<T: Copy + Ord + std::hash::Hash>(items: impl Iterator<Item = T>) -> Option<T> {
+    let mut counts: HashMap<T, usize> = HashMap::new();
+    for item in items {
+        *counts.entry(item).or_insert(0) += 1;
+    }
+    counts.into_iter().max_by_key(|(item, count)| (*count, Reverse(*item))).map(|(item, _)| item)
+}
+
+fn extract(archive: &mut ZipArchive<File>, name: &str, dest: &str) -> Result<()> {
+    let mut entry = archive.by_name(name).with_context(|| format!("{} missing from the package", name))?;
+    let mut file = File::create(dest).with_context(|| format!("Cannot create {}", dest))?;
+    io::copy(&mut entry, &mut file)?;
+    Ok(())
+}
+
+/// Card type names are used in file names, `Card 2` becomes `card-2`
+fn card_file_name(name: &str, ord: usize) -> String {
+    let name: String = name.trim()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+
+    if name.is_empty() { format!("card-{}", ord + 1) } else { name }
+}
+
+fn write_if_missing(path: &str, content: &str) -> Result<()> {
+    if !Path::new(path).exists() {
+        fs::write(path, content).with_context(|| format!("Cannot write {}", path))?;
+    }
+    Ok(())
+}
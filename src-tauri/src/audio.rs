@@ -1,18 +1,27 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
 use bytes::Bytes;
 use hex_literal::hex;
+use once_cell::sync::Lazy;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::{
-    fs::File,
-    io::{self, Cursor},
+    collections::HashMap,
     path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
+use tokio::fs;
 
 use crate::file::create_parent_dir;
 
 static URL: &str =
     "https://assets.languagepod101.com/dictionary/japanese/audiomp3.php?";
+static HOST: &str = "assets.languagepod101.com";
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
 
 const INVALID_AUDIO_HASH: [u8; 32] =
     hex!("ae6398b5a27bc8c0a771df6c907ade794be15518174773c58c7c7ddd17098906");
@@ -25,6 +34,191 @@ pub enum AudioError {
     UnavailableError(String),
     #[error("The given path is not valid: {0}")]
     PathError(String),
+    #[error("The audio server replied with status {0}, try again later")]
+    ServerError(u16),
+}
+
+/// A provider of pronunciation clips.
+///
+/// `fetch` fails with [`AudioError::UnavailableError`] when the source has no clip for the
+/// word; any other error is considered transient and the fetch may be retried later.
+#[async_trait]
+pub trait AudioSource: Send + Sync {
+    /// Name recorded on the notes whose clip was produced by this source
+    fn name(&self) -> &str;
+
+    /// Remote host requests are rate limited against, `None` for local sources
+    fn host(&self) -> Option<&str> {
+        None
+    }
+
+    async fn fetch(&self, dest: &str, opt_kanji: Option<&str>, kana: &str) -> Result<()>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum AudioSourceConfig {
+    LanguagePod101,
+    #[serde(rename_all = "camelCase")]
+    LocalFolder { path: String },
+}
+
+pub struct LanguagePod101;
+
+/// Looks up clips named `{kanji}_{kana}.mp3`, `{kanji}.mp3` or, for kana-only words,
+/// `{kana}.mp3` inside a local folder.
+pub struct LocalFolder {
+    pub path: String,
+    /// `localFolder:<path>`, so that several folders can be told apart on notes
+    name: String,
+}
+
+/// Ordered fallback chain of audio sources, sharing a per-host rate limit
+pub struct AudioSources {
+    sources: Vec<Box<dyn AudioSource>>,
+    limiter: RateLimiter,
+}
+
+/// Spaces out requests to the same host by at least `interval`
+pub struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<HashMap<String, Instant>>,
+}
+
+impl AudioSourceConfig {
+    pub fn build(&self) -> Box<dyn AudioSource> {
+        match self {
+            AudioSourceConfig::LanguagePod101 => Box::new(LanguagePod101),
+            AudioSourceConfig::LocalFolder { path } => Box::new(LocalFolder::new(path)),
+        }
+    }
+}
+
+impl LocalFolder {
+    pub fn new(path: &str) -> LocalFolder {
+        LocalFolder { path: path.to_string(), name: format!("localFolder:{}", path) }
+    }
+}
+
+#[async_trait]
+impl AudioSource for LanguagePod101 {
+    fn name(&self) -> &str {
+        "languagePod101"
+    }
+
+    fn host(&self) -> Option<&str> {
+        Some(HOST)
+    }
+
+    async fn fetch(&self, dest: &str, opt_kanji: Option<&str>, kana: &str) -> Result<()> {
+        fetch_audio(dest, opt_kanji, kana).await
+    }
+}
+
+#[async_trait]
+impl AudioSource for LocalFolder {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn fetch(&self, dest: &str, opt_kanji: Option<&str>, kana: &str) -> Result<()> {
+        if kana.is_empty() {
+            bail!(AudioError::KanaError);
+        }
+        if !Path::new(&self.path).is_dir() {
+            bail!(AudioError::PathError(self.path.clone()));
+        }
+
+        let candidates = if let Some(kanji) = opt_kanji {
+            vec![format!("{}_{}", kanji, kana), kanji.to_string()]
+        } else {
+            vec![kana.to_string()]
+        };
+
+        for name in candidates {
+            let src = format!("{}/{}.mp3", self.path, name);
+            if Path::new(&src).exists() {
+                create_parent_dir(Path::new(dest)).await?;
+                fs::copy(&src, dest).await?;
+                return Ok(());
+            }
+        }
+
+        bail!(AudioError::UnavailableError(opt_kanji.unwrap_or(kana).to_string()))
+    }
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> RateLimiter {
+        RateLimiter { interval, next_slot: Mutex::new(HashMap::new()) }
+    }
+
+    pub async fn wait(&self, host: &str) {
+        let delay = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let now = Instant::now();
+            let slot = next_slot.get(host).copied().filter(|it| *it > now).unwrap_or(now);
+            next_slot.insert(host.to_string(), slot + self.interval);
+            slot - now
+        };
+
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+impl AudioSources {
+    pub fn new(sources: Vec<Box<dyn AudioSource>>) -> AudioSources {
+        AudioSources { sources, limiter: RateLimiter::new(Duration::from_millis(200)) }
+    }
+
+    pub fn with_rate_limit(mut self, interval: Duration) -> AudioSources {
+        self.limiter = RateLimiter::new(interval);
+        self
+    }
+
+    /// Builds the chain from a deck's configuration, defaulting to LanguagePod101
+    pub fn from_config(config: &[AudioSourceConfig]) -> AudioSources {
+        if config.is_empty() {
+            return AudioSources::new(vec![Box::new(LanguagePod101)]);
+        }
+        AudioSources::new(config.iter().map(|it| it.build()).collect())
+    }
+
+    /// Tries every source in order, skipping the one named `skip`, and returns the name of the
+    /// source that produced the clip. Fails with [`AudioError::UnavailableError`] only if no
+    /// source had a transient failure.
+    pub async fn fetch(&self, dest: &str, opt_kanji: Option<&str>, kana: &str, skip: Option<&str>) -> Result<String> {
+        let mut transient_err = None;
+
+        for source in self.sources.iter() {
+            if Some(source.name()) == skip {
+                continue;
+            }
+            if let Some(host) = source.host() {
+                self.limiter.wait(host).await;
+            }
+            match source.fetch(dest, opt_kanji, kana).await {
+                Ok(_) => return Ok(source.name().to_string()),
+                Err(err) => {
+                    if !is_unavailable(&err) {
+                        transient_err = Some(err);
+                    }
+                }
+            }
+        }
+
+        if let Some(err) = transient_err {
+            return Err(err);
+        }
+
+        Err(anyhow!(AudioError::UnavailableError(opt_kanji.unwrap_or(kana).to_string())))
+    }
+}
+
+pub fn is_unavailable(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<AudioError>(), Some(AudioError::UnavailableError(_)))
 }
 
 pub async fn fetch_audio(dest: &str, opt_kanji: Option<&str>, kana: &str) -> Result<()> {
@@ -41,7 +235,17 @@ pub async fn fetch_audio(dest: &str, opt_kanji: Option<&str>, kana: &str) -> Res
 
     create_parent_dir(&Path::new(dest)).await?;
 
-    let res = reqwest::get(url).await?;
+    let res = CLIENT.get(url).send().await?;
+    let status = res.status();
+
+    // Throttling and server errors are transient, so the note is retried later
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        bail!(AudioError::ServerError(status.as_u16()));
+    }
+    if !status.is_success() {
+        bail!(AudioError::UnavailableError(opt_kanji.unwrap_or(kana).to_string()));
+    }
+
     let bytes = res.bytes().await?;
 
     if is_audio_invalid(&bytes) {
@@ -49,9 +253,7 @@ pub async fn fetch_audio(dest: &str, opt_kanji: Option<&str>, kana: &str) -> Res
         bail!(AudioError::UnavailableError(err));
     }
 
-    let mut file = File::create(dest)?;
-    let mut content = Cursor::new(bytes);
-    io::copy(&mut content, &mut file)?;
+    fs::write(dest, &bytes).await?;
 
     Ok(())
 }
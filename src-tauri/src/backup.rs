@@ -0,0 +1,100 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+use crate::deck::{Deck, DeckError};
+use crate::file::{self, backup_path, backups_path};
+
+pub const DEFAULT_BACKUP_COUNT: usize = 10;
+
+static PREFIX: &str = "deck-";
+static SUFFIX: &str = ".json";
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Backup {
+    pub name: String,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u128,
+}
+
+/// Writes `json` to a new timestamped file in `backups/`, keeping only the `keep` most recent
+pub async fn write_backup(dir: &str, json: &str, keep: usize) -> Result<Backup> {
+    let backups_dir = backups_path(dir);
+    fs::create_dir_all(&backups_dir).await
+        .with_context(|| format!("Cannot create {}", backups_dir))?;
+
+    let mut timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+    let latest = list_backups(dir).await?.first().map(|it| it.timestamp);
+
+    // Keep names unique and ordered when saving twice within the same millisecond
+    if let Some(latest) = latest {
+        timestamp = timestamp.max(latest + 1);
+    }
+
+    let backup = Backup { name: format!("{}{}{}", PREFIX, timestamp, SUFFIX), timestamp };
+    file::write_atomic(&format!("{}/{}", backups_dir, backup.name), json).await?;
+
+    for old in list_backups(dir).await?.iter().skip(keep.max(1)) {
+        fs::remove_file(format!("{}/{}", backups_dir, old.name)).await
+            .with_context(|| format!("Cannot remove backup {}", old.name))?;
+    }
+
+    Ok(backup)
+}
+
+/// Lists the backups of a deck directory, most recent first
+pub async fn list_backups(dir: &str) -> Result<Vec<Backup>> {
+    let backups_dir = backups_path(dir);
+    let mut backups = Vec::new();
+
+    if !Path::new(&backups_dir).is_dir() {
+        return Ok(backups);
+    }
+
+    let mut entries = fs::read_dir(&backups_dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(backup) = entry.file_name().to_str().and_then(parse_name) {
+            backups.push(backup);
+        }
+    }
+
+    backups.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    Ok(backups)
+}
+
+/// Reads the backup called `name`, or the most recent one. Falls back to
+/// the single `deck.json.bk` written by older versions.
+pub async fn read_backup(dir: &str, name: Option<&str>) -> Result<Deck> {
+    let backups = list_backups(dir).await?;
+
+    let backup = match name {
+        Some(name) => backups.iter().find(|it| it.name == name),
+        None => backups.first(),
+    };
+
+    if let Some(backup) = backup {
+        return Deck::from_file(&format!("{}/{}", backups_path(dir), backup.name)).await;
+    }
+
+    let legacy = backup_path(dir);
+
+    if name.is_none() && Path::new(&legacy).exists() {
+        return Deck::from_file(&legacy).await;
+    }
+
+    let missing = match name {
+        Some(name) => format!("{}/{}", backups_path(dir), name),
+        None => backups_path(dir),
+    };
+
+    bail!(DeckError::NotFoundError(missing))
+}
+
+fn parse_name(name: &str) -> Option<Backup> {
+    let timestamp = name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?.parse().ok()?;
+    Some(Backup { name: name.to_string(), timestamp })
+}
@@ -0,0 +1,313 @@
+use std::{env, io::Write, path::Path, process, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use app::{
+    apkg,
+    deck::{self, Deck, FetchOptions, Package, Template},
+    file::{create_parent_dir, deck_path, media_path, static_path, front_path, back_path, css_path, card_back_path, card_names},
+    kanjidic::KanjiReadings,
+    media::MediaIndex,
+    pitch::{self, PitchAccents},
+    progress::{CancelToken, Monitor},
+};
+use tokio::fs;
+
+static USAGE: &str = "\
+Usage: jp-anki-cli <command> <deck-dir> [options]
+
+Commands:
+    build <deck-dir>... <out.apkg> [--no-fetch] [--kanjidic=<kanjidic2.xml>]
+                                               Fetch missing audio and write an Anki package,
+                                               several deck directories giving one package
+    fetch-audio <deck-dir>                     Fetch missing pronunciations into media/
+    validate <deck-dir>                        Report notes and templates that cannot be packaged
+    migrate <deck-dir>                         Rename media to the current naming scheme
+    import <deck-dir> <package.apkg>           Create a deck directory from an Anki package
+    clean-media <deck-dir> [--delete]          List (or delete) media files that belong to no note
+    pitch <deck-dir> <accents.txt>             Add pitch accents from a Kanjium accents.txt file
+    search <deck-dir> <query>                  List notes matching a word, reading (kana or romaji) or definition
+    tag <deck-dir> <tag>... [--remove] [--notes=<id,...>]
+                                               Add (or remove) tags on all notes or the given ones
+    init-template <deck-dir> [--force]         Write the default front/back/style templates and font";
+
+static FRONT: &str = include_str!("../../res/front.html");
+static BACK: &str = include_str!("../../res/back.html");
+static CSS: &str = include_str!("../../res/style.css");
+static FONT: &[u8] = include_bytes!("../../res/_jpaf.ttf");
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if let Err(err) = run(&args).await {
+        eprintln!("error: {:#}", err);
+        process::exit(1);
+    }
+}
+
+async fn run(args: &[String]) -> Result<()> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+
+    let (positional, flags): (Vec<&String>, Vec<&String>) = rest.iter().partition(|it| !it.starts_with("--"));
+    let has_flag = |name: &str| flags.iter().any(|it| *it == name);
+    let flag_value = |name: &str| flags.iter().find_map(|it| it.strip_prefix(name)?.strip_prefix('='));
+    let dir = positional.get(0).map(|it| it.trim_end_matches('/')).with_context(|| USAGE)?;
+
+    match command {
+        "build" => {
+            let (dest, dirs) = positional.split_last().filter(|(_, dirs)| !dirs.is_empty()).with_context(|| USAGE)?;
+            let dirs: Vec<&str> = dirs.iter().map(|it| it.trim_end_matches('/')).collect();
+            build(&dirs, dest, !has_flag("--no-fetch"), flag_value("--kanjidic")).await
+        },
+        "fetch-audio" => fetch_audio(dir).await,
+        "validate" => validate(dir).await,
+        "migrate" => migrate(dir).await,
+        "clean-media" => clean_media(dir, has_flag("--delete")).await,
+        "tag" => {
+            let tags: Vec<String> = positional.iter().skip(1).map(|it| it.to_string()).collect();
+            if tags.is_empty() {
+                bail!(USAGE);
+            }
+            tag(dir, &tags, flag_value("--notes"), has_flag("--remove")).await
+        },
+        "search" => {
+            let query = positional.get(1).with_context(|| USAGE)?;
+            search(dir, query).await
+        },
+        "import" => {
+            let src = positional.get(1).with_context(|| USAGE)?;
+            import(dir, src).await
+        },
+        "pitch" => {
+            let src = positional.get(1).with_context(|| USAGE)?;
+            add_pitch(dir, src).await
+        },
+        "init-template" => init_template(dir, has_flag("--force")).await,
+        _ => bail!("Unknown command {}\n\n{}", command, USAGE),
+    }
+}
+
+async fn build(dirs: &[&str], dest: &str, fetch: bool, kanjidic: Option<&str>) -> Result<()> {
+    let readings = match kanjidic {
+        Some(path) => Some(Arc::new(KanjiReadings::load(path).await?)),
+        None => None,
+    };
+
+    let mut packages = Vec::new();
+
+    for dir in dirs {
+        if fetch {
+            fetch_audio(dir).await?;
+        }
+
+        let deck = Deck::from_file(&deck_path(dir)).await?;
+        let template = Template::from_dir(dir).await?;
+        let cards = deck.cards.clone();
+        let mut pkg = Package::new(deck, template).await?;
+
+        // New card types are appended to deck.json so that their ordinals stay the same
+        if pkg.deck.cards != cards {
+            pkg.deck.write(&deck_path(dir)).await?;
+        }
+
+        if let Some(readings) = &readings {
+            pkg = pkg.with_readings(readings.clone());
+        }
+
+        packages.push((pkg, media_path(dir), static_path(dir)));
+    }
+
+    let sources: Vec<(&Package, &str, Option<&str>)> = packages.iter()
+        .map(|(pkg, media, static_dir)| (pkg, media.as_str(), Some(static_dir.as_str())))
+        .collect();
+
+    let warnings = deck::write_packages(&sources, dest, &monitor("build")).await?;
+    eprintln!();
+
+    for warning in warnings.iter() {
+        eprintln!("warning: {}", warning);
+    }
+
+    println!("Wrote {}", dest);
+
+    Ok(())
+}
+
+async fn fetch_audio(dir: &str) -> Result<()> {
+    let mut deck = Deck::from_file(&deck_path(dir)).await?;
+    let monitor = monitor("fetch-audio");
+
+    deck::fetch_all_audio_with(&mut deck, &media_path(dir), &FetchOptions::default(), &monitor).await?;
+    eprintln!();
+    deck.write(&deck_path(dir)).await?;
+
+    if monitor.is_cancelled() {
+        bail!("Cancelled, finished notes were saved");
+    }
+
+    Ok(())
+}
+
+async fn validate(dir: &str) -> Result<()> {
+    let deck = Deck::from_file(&deck_path(dir)).await?;
+    let mut problems = deck.validate(&media_path(dir));
+
+    let cards = card_names(dir);
+
+    if cards.is_empty() {
+        problems.push(format!("Missing template {}", front_path(dir)));
+    }
+
+    for card in cards.iter() {
+        let path = card_back_path(dir, card);
+        if !Path::new(&path).exists() {
+            problems.push(format!("Missing template {}", path));
+        }
+    }
+
+    if let Ok(template) = Template::from_dir(dir).await {
+        for problem in template.check(&deck.field_names()) {
+            problems.push(problem.to_string());
+        }
+    }
+
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+
+    if !problems.is_empty() {
+        bail!("{} problem(s) found", problems.len());
+    }
+
+    println!("{}: {} notes OK", deck.name, deck.notes.len());
+    Ok(())
+}
+
+async fn migrate(dir: &str) -> Result<()> {
+    let mut deck = Deck::from_file(&deck_path(dir)).await?;
+    deck::upgrade_media_naming_with(&mut deck, &media_path(dir), &monitor("migrate")).await?;
+    eprintln!();
+    deck.write(&deck_path(dir)).await?;
+    Ok(())
+}
+
+async fn clean_media(dir: &str, delete: bool) -> Result<()> {
+    let deck = Deck::from_file(&deck_path(dir)).await?;
+    let media = MediaIndex::load(&media_path(dir)).await?;
+    let unused = deck.unused_media(&media);
+
+    for name in unused.iter() {
+        let path = media.path(name);
+
+        if delete {
+            fs::remove_file(&path).await
+                .with_context(|| format!("Failed to delete {}", path))?;
+        }
+        println!("{}", path);
+    }
+
+    println!("{} unused file(s){}", unused.len(), if delete { " deleted" } else { "" });
+    Ok(())
+}
+
+async fn import(dir: &str, src: &str) -> Result<()> {
+    let deck = apkg::import_apkg(src, dir).await?;
+    println!("Imported {} notes into {}", deck.notes.len(), dir);
+    Ok(())
+}
+
+async fn add_pitch(dir: &str, src: &str) -> Result<()> {
+    let mut deck = Deck::from_file(&deck_path(dir)).await?;
+    let accents = PitchAccents::load(src).await?;
+    let count = pitch::fill_pitch(&mut deck, &accents);
+    deck.write(&deck_path(dir)).await?;
+    println!("Added pitch accent to {} notes", count);
+    Ok(())
+}
+
+async fn tag(dir: &str, tags: &[String], notes: Option<&str>, remove: bool) -> Result<()> {
+    let mut deck = Deck::from_file(&deck_path(dir)).await?;
+
+    let note_ids: Vec<u32> = match notes {
+        Some(ids) => ids.split(',')
+            .map(|it| it.trim().parse().with_context(|| format!("Invalid note ID: {}", it)))
+            .collect::<Result<_>>()?,
+        None => deck.notes.iter().filter_map(|it| it.id).collect(),
+    };
+
+    let count = if remove { deck.remove_tags(&note_ids, tags) } else { deck.add_tags(&note_ids, tags) };
+    deck.write(&deck_path(dir)).await?;
+    println!("Updated {} notes", count);
+    Ok(())
+}
+
+async fn search(dir: &str, query: &str) -> Result<()> {
+    let deck = Deck::from_file(&deck_path(dir)).await?;
+
+    for note in deck.search(query) {
+        println!(
+            "{}\t{}\t{}\t{}",
+            note.id.unwrap_or_default(),
+            note.word,
+            note.reading.as_deref().unwrap_or(""),
+            note.definition,
+        );
+    }
+
+    Ok(())
+}
+
+async fn init_template(dir: &str, force: bool) -> Result<()> {
+    // style.css loads the font from the package media, which static/ is copied into
+    let files: [(String, &[u8]); 4] = [
+        (front_path(dir), FRONT.as_bytes()),
+        (back_path(dir), BACK.as_bytes()),
+        (css_path(dir), CSS.as_bytes()),
+        (format!("{}/_jpaf.ttf", static_path(dir)), FONT),
+    ];
+
+    for (path, _) in files.iter() {
+        if Path::new(path).exists() && !force {
+            bail!("{} already exists, use --force to overwrite", path);
+        }
+    }
+
+    for (path, content) in files.iter() {
+        create_parent_dir(Path::new(path)).await?;
+        fs::write(path, content).await
+            .with_context(|| format!("Failed to write {}", path))?;
+        println!("Wrote {}", path);
+    }
+
+    Ok(())
+}
+
+/// Prints progress on stderr and cancels on Ctrl-C
+fn monitor(task: &'static str) -> Monitor {
+    let token = CancelToken::new();
+    let ctrl_c_token = token.clone();
+
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            ctrl_c_token.cancel();
+        }
+    });
+
+    Monitor::new(token, move |progress| {
+        eprint!(
+            "\r{}: {}/{} ({} failed) {}\x1b[K",
+            task,
+            progress.done,
+            progress.total,
+            progress.failures,
+            progress.current.as_deref().unwrap_or(""),
+        );
+        let _ = std::io::stderr().flush();
+    })
+}
@@ -1,17 +1,28 @@
-use crate::audio::AudioError;
-use crate::audio;
+use crate::audio::{self, AudioError, AudioSourceConfig, AudioSources};
 use crate::ext::string::StringExt;
 use crate::file;
-use crate::kanji::rubify;
+use crate::kanji::{align_furigana, align_furigana_with, parse_ruby, RubyStyle};
+use crate::kanjidic::KanjiReadings;
+use crate::media::{self, MediaIndex};
+use crate::pitch::PitchStyle;
+use crate::romaji::{kana_to_romaji, romaji_to_kana, Romanization};
+use crate::template::{self, CardPreview, TemplateProblem, SPECIAL_FIELDS};
+use crate::progress::{Monitor, Progress, TaskError};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use std::convert::AsRef;
 use strum_macros::{AsRefStr, Display};
 use anyhow::{Context, Result, bail, anyhow};
 use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
 use serde::{Deserialize, Serialize};
 use if_chain::if_chain;
 use tokio::fs;
+use tokio::sync::{mpsc, Semaphore};
 use tokio::task::{self, JoinHandle};
+use thiserror::Error;
 
 pub static AUDIO_SUFFIX: &str = "r.mp3";
 pub static VIDEO_EXT: Lazy<Vec<&str>> = Lazy::new(|| vec!["webm", "mp4", "ogg"]);
@@ -34,6 +45,41 @@ pub static MEDIA_EXT: Lazy<Vec<&str>> = Lazy::new(|| {
     media
 });
 
+/// Fields of every note type, in model order. Custom fields of the deck follow them.
+pub static NOTE_FIELDS: [&str; 9] = [
+    "ID", "Word", "Reading", "Definition", "Pronunciation", "Media", "Transcription", "Pitch", "Romaji",
+];
+
+#[derive(Error, Debug)]
+pub enum DeckError {
+    #[error("Duplicate note ID: {0}")]
+    DuplicateIdError(u32),
+    #[error("Cannot assign more than {} note IDs", u32::MAX)]
+    IdOverflowError,
+    #[error("Failed to parse deck: {0}")]
+    ParseError(String),
+    #[error("{0} does not exist")]
+    NotFoundError(String),
+    #[error("{0} is not a valid directory")]
+    DirectoryError(String),
+    #[error("Failed to read template {0}")]
+    TemplateError(String),
+    #[error("File already exists: {0}")]
+    FileExistsError(String),
+    #[error("Unsupported media file: {0}")]
+    MediaError(String),
+    #[error("Another media file of a different content has the same name: {0}")]
+    MediaConflictError(String),
+    #[error("Deck ID {0} is used by both {1} and {2}")]
+    DuplicateDeckIdError(usize, String, String),
+    #[error("Note {0}: {1}")]
+    NoteError(u32, String),
+    #[error("Invalid field name: {0}")]
+    FieldError(String),
+    #[error("Invalid template: {0}")]
+    InvalidTemplateError(String),
+}
+
 #[derive(AsRefStr, Display)]
 pub enum AudioState {
     OK,
@@ -41,6 +87,16 @@ pub enum AudioState {
     RETRY,
 }
 
+/// How media files and note GUIDs are derived from the deck and note ids
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum MediaNaming {
+    /// `{deck_id}{note_id}`, ambiguous: deck 1 / note 12 and deck 11 / note 2 collide
+    Legacy,
+    /// `{deck_id}_{note_id}`
+    Separated,
+}
+
 pub enum MediaType {
     VIDEO,
     IMAGE,
@@ -48,16 +104,27 @@ pub enum MediaType {
     INVALID,
 }
 
+/// Tuning of bulk audio fetching
+pub struct FetchOptions {
+    /// Maximum number of notes fetched concurrently
+    pub workers: usize,
+    /// Attempts per note before it is left in the `RETRY` state
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every subsequent attempt
+    pub backoff: Duration,
+}
+
 pub struct Package {
     pub deck: Deck,
     pub template: Template,
+    pub readings: Option<Arc<KanjiReadings>>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Note {
     #[serde(default)]
-    pub id: Option<u16>,
+    pub id: Option<u32>,
     pub word: String,
     pub reading: Option<String>,
     pub definition: String,
@@ -65,41 +132,131 @@ pub struct Note {
     #[serde(default)]
     pub audio_state: Option<String>,
     #[serde(default)]
-    pub use_reading: bool
+    pub use_reading: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_source: Option<String>,
+    /// Pitch accents, each the mora after which pitch drops (0 for none)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pitch: Option<Vec<u8>>,
+    /// Values of the custom fields of the deck, by field name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Sub-deck of the deck the note's cards go to, like `Show A::Ep 3`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub_deck: Option<String>,
 }
 
-pub struct Template {
+/// Values of the note type fields of a note, see `Package::note_fields`
+pub struct NoteFields {
+    pub values: Vec<String>,
+    /// Paths of the media files referenced by the values
+    pub media: Vec<String>,
+    pub warning: Option<String>,
+}
+
+/// An Anki deck of a package, the deck itself or one of its sub-decks
+#[derive(Debug, PartialEq, Eq)]
+pub struct SubDeck {
+    pub id: usize,
+    /// Full name, like `Mining::Show A::Ep 3`
+    pub name: String,
+    pub note_ids: Vec<u32>,
+}
+
+/// A card type of the model, each note gives one card per type
+pub struct CardTemplate {
+    pub name: String,
     pub front: String,
     pub back: String,
+}
+
+pub struct Template {
+    pub cards: Vec<CardTemplate>,
     pub css: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Deck {
     pub id: usize,
+    /// ID of the note type in Anki, set to the deck ID when missing and kept when the deck
+    /// ID changes, so that Anki keeps updating the same note type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_id: Option<usize>,
     pub name: String,
     #[serde(default)]
     pub description: Option<String>,
+    #[serde(default)]
+    pub media_naming: MediaNaming,
+    #[serde(default)]
+    pub ruby_style: RubyStyle,
+    #[serde(default)]
+    pub pitch_style: PitchStyle,
+    /// Fills the Romaji field of packages when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub romaji: Option<Romanization>,
+    /// Custom fields added to the note type after `NOTE_FIELDS`, in this order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<String>,
+    /// Card types in the order of their ordinals in Anki, new ones being appended
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cards: Vec<String>,
+    /// Tags given to every note of the deck in packages
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audio_sources: Vec<AudioSourceConfig>,
     pub notes: Vec<Note>,
 }
 
+impl Default for MediaNaming {
+    fn default() -> Self {
+        MediaNaming::Legacy
+    }
+}
+
+impl MediaNaming {
+    pub fn media_key(&self, deck_id: usize, note_id: u32) -> String {
+        match self {
+            MediaNaming::Legacy => format!("{}{}", deck_id, note_id),
+            MediaNaming::Separated => format!("{}_{}", deck_id, note_id),
+        }
+    }
+
+    pub fn guid(&self, deck_id: usize, note_id: u32) -> String {
+        match self {
+            MediaNaming::Legacy => format!("{}{}{}", deck_id, note_id, deck_id),
+            MediaNaming::Separated => format!("{}_{}", deck_id, note_id),
+        }
+    }
+}
+
 impl Deck {
     pub async fn from_file(path: &str) -> Result<Deck> {
         if !Path::new(path).exists() {
-            bail!("{} does not exist", path);
+            bail!(DeckError::NotFoundError(path.to_string()));
         }
 
         let data = fs::read_to_string(path).await
             .with_context(|| format!("Cannot read {}", path))?;
-        let mut deck: Deck = serde_json::from_str(&data)?;
-        deck.assign_ids();
+        let mut deck: Deck = serde_json::from_str(&data)
+            .map_err(|err| DeckError::ParseError(err.to_string()))?;
+        deck.assign_ids()?;
+        deck.check_fields()?;
+        deck.model_id.get_or_insert(deck.id);
         Ok(deck)
     }
 
     pub fn from_json(json: &str) -> Result<Deck> {
-        let mut deck: Deck = serde_json::from_str(json).with_context(|| "Failed to deserialize deck")?;
-        deck.assign_ids();
+        let mut deck: Deck = serde_json::from_str(json)
+            .map_err(|err| DeckError::ParseError(err.to_string()))?;
+        deck.assign_ids()?;
+        deck.check_fields()?;
+        deck.model_id.get_or_insert(deck.id);
         Ok(deck)
     }
 
@@ -107,7 +264,7 @@ impl Deck {
         let json = serde_json::to_string_pretty(&self)
             .with_context(|| "Failed to serialize deck.json")?;
 
-        fs::write(dest, json).await
+        file::write_atomic(dest, json).await
             .with_context(|| "Failed to write deck.json")?;
 
         Ok(())
@@ -117,144 +274,563 @@ impl Deck {
         Ok(serde_json::to_string(self)?)
     }
 
-    pub fn assign_ids(&mut self) {
-        let mut max_id = 0;
+    /// Lists the notes that would fail or lose content when packaged
+    pub fn validate(&self, media_dir: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        let media = match MediaIndex::scan(media_dir) {
+            Ok(media) => media,
+            Err(err) => return vec![format!("{:#}", err)],
+        };
 
         for note in self.notes.iter() {
+            let id = match note.id {
+                Some(id) => id,
+                None => {
+                    problems.push(format!("Note {} has no ID", note.word));
+                    continue;
+                }
+            };
+
             if_chain! {
-                if let Some(id) = note.id;
-                if id > max_id;
+                if !note.word.contains_ruby() && !note.use_reading;
+                if let Some(reading) = &note.reading;
+                if let (_, Some(warning)) = align_furigana(&note.word, reading);
                 then {
+                    problems.push(alignment_warning(id, note, &warning));
+                }
+            }
+
+            for name in note.fields.keys().filter(|it| !self.fields.contains(it)) {
+                problems.push(format!("Note {} ({}): field {} is not in the deck's fields", id, note.word, name));
+            }
+
+            let audio_name = format!("{}{}", self.media_key(id), AUDIO_SUFFIX);
+
+            if is_audio_ok(&note.audio_state) && !media.contains(&audio_name) {
+                problems.push(format!("Note {} ({}): missing pronunciation {}", id, note.word, media.path(&audio_name)));
+            }
+        }
+
+        problems
+    }
+
+    /// Files of the media directory that belong to no note, like the media of deleted notes
+    /// or of notes renamed by a migration. Legacy decks may also name files after the word.
+    pub fn unused_media<'a>(&self, media: &'a MediaIndex) -> Vec<&'a str> {
+        let mut stems: HashSet<String> = HashSet::new();
+
+        for note in self.notes.iter() {
+            if let Some(id) = note.id {
+                let key = self.media_key(id);
+                stems.insert(media::file_stem(&format!("{}{}", key, AUDIO_SUFFIX)).to_string());
+                stems.insert(key);
+            }
+            if self.media_naming == MediaNaming::Legacy {
+                stems.insert(note.word.clone());
+                stems.insert(format!("{}_r", note.word));
+            }
+        }
+
+        media.unused(&stems)
+    }
+
+    /// Notes whose word, reading or definition contains `query`, which may be written in romaji
+    pub fn search(&self, query: &str) -> Vec<&Note> {
+        let query = query.trim().to_lowercase();
+
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let kana = romaji_to_kana(&query).to_hiragana();
+
+        self.notes.iter()
+            .filter(|note| {
+                let reading = note.kana();
+                note.word.contains(&query)
+                    || note.definition.to_lowercase().contains(&query)
+                    || reading.to_hiragana().contains(&kana)
+                    || kana_to_romaji(&reading, Romanization::Hepburn).contains(&query)
+                    || kana_to_romaji(&reading, Romanization::Kunrei).contains(&query)
+            })
+            .collect()
+    }
+
+    /// Base name (without extension) of the media files belonging to a note
+    pub fn media_key(&self, note_id: u32) -> String {
+        self.media_naming.media_key(self.id, note_id)
+    }
+
+    /// GUID of the note in Anki. A pinned GUID takes precedence so notes keep updating
+    /// the same Anki notes after the naming scheme changes.
+    pub fn note_guid(&self, note: &Note) -> Result<String> {
+        if let Some(guid) = &note.guid {
+            return Ok(guid.clone());
+        }
+        let id = note.id.with_context(|| format!("Note {} has no ID", note.word))?;
+        Ok(self.media_naming.guid(self.id, id))
+    }
+
+    /// Orders the card types of `template` as listed in `cards`, appending new ones to the
+    /// list. Anki matches cards to their template by position, so the order never changes.
+    pub fn order_cards(&mut self, template: &mut Template) {
+        for card in template.cards.iter() {
+            if !self.cards.contains(&card.name) {
+                self.cards.push(card.name.clone());
+            }
+        }
+
+        let cards = &self.cards;
+        template.cards.sort_by_key(|card| cards.iter().position(|it| *it == card.name));
+    }
+
+    /// Names of the note type fields: `NOTE_FIELDS` then the custom ones
+    pub fn field_names(&self) -> Vec<String> {
+        NOTE_FIELDS.iter().map(|it| it.to_string()).chain(self.fields.iter().cloned()).collect()
+    }
+
+    /// Rejects custom fields that are empty, repeated, named like a built-in field
+    /// or containing characters Anki does not allow in field names
+    pub fn check_fields(&self) -> Result<()> {
+        let mut names: HashSet<String> = NOTE_FIELDS.iter().chain(SPECIAL_FIELDS.iter())
+            .map(|it| it.to_lowercase())
+            .collect();
+
+        for field in self.fields.iter() {
+            let invalid = field.trim().is_empty()
+                || field.trim() != field
+                || field.contains(|c| ":{}\"".contains(c))
+                || field.starts_with(|c| "#/^".contains(c));
+
+            if invalid || !names.insert(field.to_lowercase()) {
+                bail!(DeckError::FieldError(field.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tags of a note in packages: the deck tags then the note's own, without duplicates.
+    /// Anki ignores case in tags, so the first spelling of a tag is kept.
+    pub fn note_tags(&self, note: &Note) -> Vec<String> {
+        let mut tags: Vec<String> = Vec::new();
+
+        for tag in self.tags.iter().chain(note.tags.iter()).filter_map(|it| normalize_tag(it)) {
+            if !has_tag(&tags, &tag) {
+                tags.push(tag);
+            }
+        }
+
+        tags
+    }
+
+    pub fn note_type_id(&self) -> usize {
+        self.model_id.unwrap_or(self.id)
+    }
+
+    /// Full Anki name of the deck a note goes to: the deck name, followed by the sub-deck if any
+    pub fn sub_deck_name(&self, note: &Note) -> String {
+        let path: Vec<&str> = note.sub_deck.iter()
+            .flat_map(|it| it.split("::"))
+            .map(|it| it.trim())
+            .filter(|it| !it.is_empty())
+            .collect();
+
+        if path.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}::{}", self.name, path.join("::"))
+        }
+    }
+
+    /// Sub-deck IDs are derived from the deck ID and the sub-deck name so they stay the same across packages
+    pub fn sub_deck_id(&self, name: &str) -> usize {
+        if name == self.name {
+            return self.id;
+        }
+        let hash = Sha256::digest(format!("{}:{}", self.id, name).as_bytes());
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&hash[..8]);
+        // 52 bits, to stay exact as a JavaScript number
+        (u64::from_be_bytes(bytes) >> 12) as usize
+    }
+
+    /// Anki decks the notes go to: the deck itself, then its sub-decks in order of first use
+    pub fn sub_decks(&self) -> Vec<SubDeck> {
+        let mut decks = vec![SubDeck { id: self.id, name: self.name.clone(), note_ids: Vec::new() }];
+
+        for note in self.notes.iter() {
+            let name = self.sub_deck_name(note);
+            let position = match decks.iter().position(|it| it.name == name) {
+                Some(position) => position,
+                None => {
+                    decks.push(SubDeck { id: self.sub_deck_id(&name), name, note_ids: Vec::new() });
+                    decks.len() - 1
+                }
+            };
+            decks[position].note_ids.extend(note.id);
+        }
+
+        decks
+    }
+
+    /// Adds tags to the notes with the given IDs, returns the number of notes changed
+    pub fn add_tags(&mut self, note_ids: &[u32], tags: &[String]) -> usize {
+        let tags: Vec<String> = tags.iter().filter_map(|it| normalize_tag(it)).collect();
+        let mut count = 0;
+
+        for note in self.notes.iter_mut().filter(|it| it.id.map_or(false, |id| note_ids.contains(&id))) {
+            let len = note.tags.len();
+            for tag in tags.iter() {
+                if !has_tag(&note.tags, tag) {
+                    note.tags.push(tag.clone());
+                }
+            }
+            if note.tags.len() != len {
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    /// Removes tags from the notes with the given IDs. Deck tags apply to every note, so they
+    /// are only removed when all notes are given. Returns the number of notes whose tags in
+    /// packages changed.
+    pub fn remove_tags(&mut self, note_ids: &[u32], tags: &[String]) -> usize {
+        let tags: Vec<String> = tags.iter().filter_map(|it| normalize_tag(it)).collect();
+        let before: Vec<Vec<String>> = self.notes.iter().map(|it| self.note_tags(it)).collect();
+
+        if self.notes.iter().all(|it| it.id.map_or(false, |id| note_ids.contains(&id))) {
+            self.tags.retain(|it| normalize_tag(it).map_or(true, |it| !has_tag(&tags, &it)));
+        }
+        for note in self.notes.iter_mut().filter(|it| it.id.map_or(false, |id| note_ids.contains(&id))) {
+            note.tags.retain(|it| !has_tag(&tags, it));
+        }
+
+        self.notes.iter().zip(before).filter(|(note, tags)| self.note_tags(note) != *tags).count()
+    }
+
+    /// Gives an ID to every note lacking one, rejecting decks with duplicate IDs.
+    /// Older decks stored IDs as `u16` and deserialize as-is.
+    pub fn assign_ids(&mut self) -> Result<()> {
+        let mut ids: HashSet<u32> = HashSet::new();
+        let mut max_id = 0;
+
+        for note in self.notes.iter() {
+            if let Some(id) = note.id {
+                if !ids.insert(id) {
+                    bail!(DeckError::DuplicateIdError(id));
+                }
+                if id > max_id {
                     max_id = id;
                 }
             }
         }
 
-        for mut note in self.notes.iter_mut() {
+        for note in self.notes.iter_mut() {
             if note.id.is_none() {
-                note.id = Some(max_id + 1);
-                max_id += 1;
+                max_id = max_id.checked_add(1).ok_or(DeckError::IdOverflowError)?;
+                note.id = Some(max_id);
             }
         }
+
+        Ok(())
+    }
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        FetchOptions { workers: 8, max_attempts: 4, backoff: Duration::from_millis(500) }
+    }
+}
+
+impl Note {
+    /// Reading of the word in kana, from its ruby, its reading or the word itself
+    pub fn kana(&self) -> String {
+        if self.word.contains_ruby() {
+            self.word.ruby_reading()
+        } else {
+            self.reading.clone().unwrap_or_else(|| self.word.clone())
+        }
+    }
+
+    /// Kanji and kana the pronunciation is looked up with
+    fn audio_terms(&self) -> (Option<String>, String) {
+        match &self.reading {
+            Some(reading) => (Some(self.word.clone()), reading.clone()),
+            None => (None, self.word.clone()),
+        }
     }
 }
 
 impl Template {
+    /// Reads `front.html`/`back.html` as the `Default` card type and every
+    /// `<name>.front.html`/`<name>.back.html` pair as a card type called `<name>`
     pub async fn from_dir(path: &str) -> Result<Template> {
-        let front = fs::read_to_string(format!("{}/front.html", path)).await
-            .with_context(|| format!("Failed to read front template {}", path))?;
+        let names = file::card_names(path);
+
+        if names.is_empty() {
+            bail!(DeckError::TemplateError(file::front_path(path)));
+        }
+
+        let mut cards = Vec::new();
 
-        let back = fs::read_to_string(format!("{}/back.html", path)).await
-            .with_context(|| format!("Failed to read back template {}", path))?;
+        for name in names {
+            let front_path = file::card_front_path(path, &name);
+            let front = fs::read_to_string(&front_path).await
+                .with_context(|| DeckError::TemplateError(front_path))?;
 
-        let css = fs::read_to_string(format!("{}/style.css", path)).await
+            let back_path = file::card_back_path(path, &name);
+            let back = fs::read_to_string(&back_path).await
+                .with_context(|| DeckError::TemplateError(back_path))?;
+
+            cards.push(CardTemplate { name, front, back });
+        }
+
+        let css = fs::read_to_string(file::css_path(path)).await
             .unwrap_or(String::new());
 
-        Ok(Template { front, back, css })
+        Ok(Template { cards, css })
     }
 
-    pub fn to_model(&self, id: usize, name: &str) -> genanki_rs::Model {
+    pub fn to_model(&self, id: usize, name: &str, fields: &[String]) -> genanki_rs::Model {
         genanki_rs::Model::new_with_options(
             id,
             name,
-            vec![
-                genanki_rs::Field::new("ID"),
-                genanki_rs::Field::new("Word"),
-                genanki_rs::Field::new("Reading"),
-                genanki_rs::Field::new("Definition"),
-                genanki_rs::Field::new("Pronunciation"),
-                genanki_rs::Field::new("Media"),
-                genanki_rs::Field::new("Transcription"),
-            ],
-            vec![genanki_rs::Template::new("Default").qfmt(&self.front).afmt(&self.back)],
+            fields.iter().map(|it| genanki_rs::Field::new(it)).collect(),
+            self.cards.iter()
+                .map(|card| genanki_rs::Template::new(&card.name).qfmt(&card.front).afmt(&card.back))
+                .collect(),
             Some(&self.css), None, None, None, Some(0),
         )
     }
+
+    /// Checks the tags of every card template against the note type fields
+    pub fn check(&self, fields: &[String]) -> Vec<TemplateProblem> {
+        let mut problems = Vec::new();
+
+        for card in self.cards.iter() {
+            for (side, text) in [("front", &card.front), ("back", &card.back)] {
+                for (line, message) in template::check_tags(text, fields) {
+                    problems.push(TemplateProblem { card: card.name.clone(), side: side.to_string(), line, message });
+                }
+            }
+        }
+
+        problems
+    }
 }
 
 impl Package {
-    pub async fn new(deck: Deck, template: Template) -> Result<Package> {
-        Ok(Package { deck, template })
+    pub async fn new(mut deck: Deck, mut template: Template) -> Result<Package> {
+        deck.order_cards(&mut template);
+        Ok(Package { deck, template, readings: None })
     }
 
-    pub async fn write(&mut self, dest: &str, media_dir: &str, static_dir: Option<&str>) -> Result<()> {
-        let mut deck = genanki_rs::Deck::new(
-            self.deck.id,
-            &self.deck.name,
-            &self.deck.description.as_ref().unwrap_or(&"".to_string())
-        );
-
-        let mut media_list: Vec<String> = Vec::new();
+    /// Uses the readings of each kanji to place furigana
+    pub fn with_readings(mut self, readings: Arc<KanjiReadings>) -> Package {
+        self.readings = Some(readings);
+        self
+    }
 
-        for (index, note) in self.deck.notes.iter_mut().enumerate() {
-            let word_contains_reading = note.word.contains_ruby();
+    /// Field values of a note in `Deck::field_names` order, with the media files they
+    /// reference. `index` is the position of the note in the deck, written to the ID field.
+    pub fn note_fields(&self, index: usize, note: &Note, media: &MediaIndex) -> Result<NoteFields> {
+        let id = note.id.with_context(|| format!("Note {} has no ID", note.word))?;
+        let word_contains_reading = note.word.contains_ruby();
+        let mut warning = None;
 
-            let reading = if word_contains_reading {
-                note.word.format_ruby()
-            } else if let Some(reading) = &note.reading {
-                if note.use_reading {
-                    reading.to_string()
-                } else {
-                    rubify(&note.word, reading)
-                        .with_context(|| format!("Failed to generate ruby string for {}({})", note.word, reading))?
-                }
+        let reading = if word_contains_reading {
+            self.deck.ruby_style.render(&parse_ruby(&note.word))
+        } else if let Some(reading) = &note.reading {
+            if note.use_reading {
+                reading.to_string()
             } else {
-                String::new()
-            };
-                        
-            let word = if word_contains_reading {
-                note.word.remove_ruby()
-            } else if note.use_reading {
-                reading.clone()
-            } else {
-                note.word.clone()
-            };
+                let (segments, alignment) = align_furigana_with(&note.word, reading, self.readings.as_deref());
+                warning = alignment.map(|it| alignment_warning(id, note, &it));
+                self.deck.ruby_style.render(&segments)
+            }
+        } else {
+            String::new()
+        };
 
-            let id = note.id.with_context(|| format!("Note {} has no ID", note.word))?;
-            let filename = format!("{}{}", self.deck.id, id);
-            let media_base_path = format!("{}/{}", media_dir, filename);
-            let pronun_path = format!("{}{}", media_base_path, AUDIO_SUFFIX);
-            let media_ext = file::find_extension(&media_base_path, &(*MEDIA_EXT));
+        let word = if word_contains_reading {
+            note.word.remove_ruby()
+        } else if note.use_reading {
+            reading.clone()
+        } else {
+            note.word.clone()
+        };
 
-            let media = if let Some(ext) = media_ext {
-                media_list.push(format!("{}.{}", media_base_path, ext));
-                format!("[sound:{}.{}]", filename, ext)
-            } else {
-                String::new()
-            };
+        let filename = self.deck.media_key(id);
+        let media_names = media.media(&filename);
+        let mut media_files: Vec<String> = media_names.iter().map(|it| media.path(it)).collect();
+        let media_field = media_names.iter().map(|it| format!("[sound:{}]", it)).collect::<String>();
+
+        let pronunciation = format!("[sound:{}{}]", filename, AUDIO_SUFFIX);
+
+        if is_audio_ok(&note.audio_state) {
+            media_files.push(media.path(&format!("{}{}", filename, AUDIO_SUFFIX)));
+        }
+
+        let pitch = note.pitch.as_ref()
+            .map(|accents| self.deck.pitch_style.render(&note.kana(), accents))
+            .unwrap_or_default();
+
+        let romaji = self.deck.romaji
+            .map(|system| kana_to_romaji(&note.kana(), system))
+            .unwrap_or_default();
+
+        let mut values = vec![
+            (index + 1).to_string(),
+            word,
+            if note.use_reading { String::new() } else { reading },
+            note.definition.clone(),
+            pronunciation,
+            media_field,
+            note.transcription.clone(),
+            pitch,
+            romaji,
+        ];
+
+        for field in self.deck.fields.iter() {
+            values.push(note.fields.get(field).cloned().unwrap_or_default());
+        }
+
+        Ok(NoteFields { values, media: media_files, warning })
+    }
+
+    /// Note type of the package, built and checked once per package. genanki-rs takes the
+    /// note type by value, so each note still gets its own copy of it. Fails when the
+    /// templates reference unknown fields or are malformed.
+    pub fn model(&self) -> Result<genanki_rs::Model> {
+        let fields = self.deck.field_names();
 
-            let pronunciation = format!("[sound:{}{}]", filename, AUDIO_SUFFIX);
-            let has_pronunciation: bool = is_audio_ok(&note.audio_state);
+        if let Some(problem) = self.template.check(&fields).into_iter().next() {
+            bail!(DeckError::InvalidTemplateError(problem.to_string()));
+        }
+
+        Ok(self.template.to_model(self.deck.note_type_id(), &self.deck.name, &fields))
+    }
+
+    /// Renders a card of `note`, the first card type unless `card` is given, without writing a package
+    pub async fn preview(&self, note: &Note, card: Option<&str>, media_dir: &str) -> Result<CardPreview> {
+        let card = match card {
+            Some(name) => self.template.cards.iter().find(|it| it.name == name),
+            None => self.template.cards.first(),
+        }.with_context(|| DeckError::TemplateError(card.unwrap_or(file::DEFAULT_CARD).to_string()))?;
+
+        let index = self.deck.notes.iter()
+            .position(|it| it.id.is_some() && it.id == note.id)
+            .unwrap_or(self.deck.notes.len());
+
+        let values = self.note_fields(index, note, &MediaIndex::load(media_dir).await?)?.values;
+        let mut fields: HashMap<String, String> = self.deck.field_names().into_iter().zip(values).collect();
+
+        fields.insert("Deck".to_string(), self.deck.name.clone());
+        fields.insert("Type".to_string(), self.deck.name.clone());
+        fields.insert("Card".to_string(), card.name.clone());
+        fields.insert("Tags".to_string(), self.deck.note_tags(note).join(" "));
+
+        let front = template::render(&card.front, &fields);
+        fields.insert("FrontSide".to_string(), front.clone());
+        let back = template::render(&card.back, &fields);
+
+        Ok(CardPreview { front, back, css: self.template.css.clone() })
+    }
 
-            if has_pronunciation {
-                media_list.push(pronun_path);
+    pub async fn write(&mut self, dest: &str, media_dir: &str, static_dir: Option<&str>) -> Result<Vec<String>> {
+        self.write_with(dest, media_dir, static_dir, &Monitor::silent()).await
+    }
+
+    /// Writes the package, reporting progress per note. Nothing is written if cancelled.
+    /// Returns warnings about readings that could not be aligned to their word.
+    pub async fn write_with(&mut self, dest: &str, media_dir: &str, static_dir: Option<&str>, monitor: &Monitor) -> Result<Vec<String>> {
+        write_packages(&[(&*self, media_dir, static_dir)], dest, monitor).await
+    }
+
+    /// Anki decks of the package, the deck itself followed by its sub-decks, with the
+    /// media files referenced by the notes and the warnings about their readings
+    fn anki_decks(&self, media: &MediaIndex, monitor: &Monitor, progress: &mut Progress) -> Result<(Vec<genanki_rs::Deck>, Vec<String>, Vec<String>)> {
+        let model = self.model()?;
+        let sub_decks = self.deck.sub_decks();
+        let mut decks: Vec<genanki_rs::Deck> = sub_decks.iter()
+            .map(|it| {
+                let description = if it.id == self.deck.id { self.deck.description.as_deref() } else { None };
+                genanki_rs::Deck::new(it.id, &it.name, description.unwrap_or_default())
+            })
+            .collect();
+        let mut media_list: Vec<String> = Vec::new();
+        let mut warnings: Vec<String> = Vec::new();
+
+        for (index, note) in self.deck.notes.iter().enumerate() {
+            if monitor.is_cancelled() {
+                bail!(TaskError::CancelledError);
             }
-            
-            let guid = format!("{}{}", filename, self.deck.id);
-            
-            deck.add_note(genanki_rs::Note::new_with_options(
-                self.template.to_model(self.deck.id, &self.deck.name),
-                vec![
-                    &(index + 1).to_string(),
-                    &word,
-                    if note.use_reading { "" } else { &reading },
-                    &note.definition,
-                    &pronunciation,
-                    &media,
-                    &note.transcription,
-                ],
-                None,
+
+            let note_fields = self.note_fields(index, note, media)?;
+            warnings.extend(note_fields.warning);
+            media_list.extend(note_fields.media);
+
+            let guid = self.deck.note_guid(note)?;
+            let tags = self.deck.note_tags(note);
+            let deck_name = self.deck.sub_deck_name(note);
+
+            let position = sub_decks.iter().position(|it| it.name == deck_name).unwrap_or_default();
+
+            decks[position].add_note(genanki_rs::Note::new_with_options(
+                model.clone(),
+                note_fields.values.iter().map(|it| it.as_str()).collect(),
                 None,
+                Some(tags.iter().map(|it| it.as_str()).collect()),
                 Some(&guid)
             )?);
+
+            progress.done += 1;
+            progress.current = Some(note.word.clone());
+            monitor.report(progress);
         }
 
+        Ok((decks, media_list, warnings))
+    }
+    
+    pub fn to_deck(self) -> Deck {
+        return self.deck
+    }
+}
+
+/// Writes the decks of several packages into one .apkg, each package being given with its
+/// media and static directories. Returns the warnings of all packages. Writing the .apkg
+/// itself is reported as the last step and cannot be cancelled once started.
+pub async fn write_packages(packages: &[(&Package, &str, Option<&str>)], dest: &str, monitor: &Monitor) -> Result<Vec<String>> {
+    let mut decks: Vec<genanki_rs::Deck> = Vec::new();
+    let mut media_list: Vec<String> = Vec::new();
+    let mut warnings: Vec<String> = Vec::new();
+    let total = packages.iter().map(|(pkg, _, _)| pkg.deck.notes.len()).sum::<usize>() + 1;
+    let mut progress = Progress { total, ..Default::default() };
+
+    // Anki would merge decks with the same ID into one
+    let mut deck_ids: HashMap<usize, String> = HashMap::new();
+    for sub_deck in packages.iter().flat_map(|(pkg, _, _)| pkg.deck.sub_decks()) {
+        if let Some(other) = deck_ids.insert(sub_deck.id, sub_deck.name.clone()) {
+            bail!(DeckError::DuplicateDeckIdError(sub_deck.id, other, sub_deck.name));
+        }
+    }
+
+    for (pkg, media_dir, static_dir) in packages.iter() {
+        let media = MediaIndex::load(media_dir).await?;
+        let (pkg_decks, pkg_media, pkg_warnings) = pkg.anki_decks(&media, monitor, &mut progress)?;
+        decks.extend(pkg_decks);
+        media_list.extend(pkg_media);
+        warnings.extend(pkg_warnings);
+
         if_chain! {
             if let Some(static_dir) = static_dir;
             if Path::new(&static_dir).exists();
             then {
                 let mut static_files = fs::read_dir(&static_dir).await?;
-    
+
                 while let Some(file) = static_files.next_entry().await? {
                     let path = file.path().into_os_string().into_string()
                         .map_err(|_| anyhow!("Cannot read static file: {}", file.path().display()))?;
@@ -262,53 +838,200 @@ impl Package {
                 }
             }
         }
+    }
 
-        let dest = dest.to_string();
+    let media_list = dedupe_media(media_list).await?;
 
-        let pkg_task: JoinHandle<Result<()>> = task::spawn_blocking(move || {
-            let mut pkg = genanki_rs::Package::new(vec![deck], media_list.iter().map(|s| &**s).collect())?;
-            Ok(pkg.write_to_file(&dest)?)
-        });
+    if monitor.is_cancelled() {
+        bail!(TaskError::CancelledError);
+    }
 
-        pkg_task.await
-            .with_context(|| "Error while creating the package")? // task error
-            .with_context(|| "Failed to write the package")?;     // file write error
-        
-        Ok(())
+    progress.current = Some(format!("Writing {}", dest));
+    monitor.report(&progress);
+
+    let dest = dest.to_string();
+
+    let pkg_task: JoinHandle<Result<()>> = task::spawn_blocking(move || {
+        let mut pkg = genanki_rs::Package::new(decks, media_list.iter().map(|s| &**s).collect())?;
+        Ok(pkg.write_to_file(&dest)?)
+    });
+
+    pkg_task.await
+        .with_context(|| "Error while creating the package")? // task error
+        .with_context(|| "Failed to write the package")?;     // file write error
+
+    progress.done += 1;
+    progress.current = None;
+    monitor.report(&progress);
+
+    Ok(warnings)
+}
+
+/// Packages sharing static files, like fonts, would otherwise add them twice. Files are kept
+/// once per name when they are the same file or have the same content, other name clashes
+/// are rejected as Anki keeps a single file per name.
+async fn dedupe_media(media_list: Vec<String>) -> Result<Vec<String>> {
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut deduped: Vec<String> = Vec::new();
+
+    for path in media_list {
+        let name = Path::new(&path).file_name().map(|it| it.to_string_lossy().to_string()).unwrap_or_default();
+
+        match names.get(&name) {
+            Some(other) => {
+                if !same_file(other, &path).await? {
+                    bail!(DeckError::MediaConflictError(path));
+                }
+            },
+            None => {
+                names.insert(name, path.clone());
+                deduped.push(path);
+            },
+        }
     }
-    
-    pub fn to_deck(self) -> Deck {
-        return self.deck
+
+    Ok(deduped)
+}
+
+async fn same_file(a: &str, b: &str) -> Result<bool> {
+    if fs::canonicalize(a).await? == fs::canonicalize(b).await? {
+        return Ok(true);
     }
+    let a = fs::read(a).await.with_context(|| format!("Cannot read {}", a))?;
+    let b = fs::read(b).await.with_context(|| format!("Cannot read {}", b))?;
+    Ok(a == b)
 }
 
 pub async fn fetch_all_audio(deck: &mut Deck, dest_dir: &str) -> Result<()> {
-    for mut note in deck.notes.iter_mut() {
+    fetch_all_audio_with(deck, dest_dir, &FetchOptions::default(), &Monitor::silent()).await
+}
+
+/// Fetches the missing pronunciations of a deck with a bounded pool of workers,
+/// retrying transient failures with exponential backoff. When cancelled, pending notes
+/// are left untouched and the results of finished ones are kept.
+pub async fn fetch_all_audio_with(deck: &mut Deck, dest_dir: &str, options: &FetchOptions, monitor: &Monitor) -> Result<()> {
+    let naming = deck.media_naming;
+    let sources = Arc::new(AudioSources::from_config(&deck.audio_sources));
+    let semaphore = Arc::new(Semaphore::new(options.workers.max(1)));
+    let (tx, mut rx) = mpsc::unbounded_channel::<(usize, Result<String>)>();
+    let mut progress = Progress::default();
+
+    for (index, note) in deck.notes.iter_mut().enumerate() {
         let id = note.id.with_context(|| "ID must be defined")?;
-        let audio_path = format!("{}/{}{}{}", dest_dir, deck.id, id, AUDIO_SUFFIX);
+        let audio_path = format!("{}/{}{}", dest_dir, naming.media_key(deck.id, id), AUDIO_SUFFIX);
 
         if Path::new(&audio_path).exists() {
             note.audio_state = Some(AudioState::OK.to_string());
-        } else if !is_audio_unavailable(&note.audio_state) {
-            fetch_audio(&mut note, &audio_path).await;
+            continue;
+        } else if is_audio_unavailable(&note.audio_state) {
+            continue;
         }
+
+        let sources = sources.clone();
+        let semaphore = semaphore.clone();
+        let token = monitor.token();
+        let tx = tx.clone();
+        let (kanji, kana) = note.audio_terms();
+        let (max_attempts, backoff) = (options.max_attempts, options.backoff);
+
+        progress.total += 1;
+
+        task::spawn(async move {
+            let _permit = match semaphore.acquire_owned().await {
+                Ok(permit) => permit,
+                Err(_) => return,
+            };
+            if token.is_cancelled() {
+                return;
+            }
+            let res = fetch_with_backoff(&sources, &audio_path, kanji.as_deref(), &kana, max_attempts, backoff).await;
+            let _ = tx.send((index, res));
+        });
     }
+
+    drop(tx);
+
+    while let Some((index, res)) = rx.recv().await {
+        let note = &mut deck.notes[index];
+        set_audio_result(note, res);
+
+        progress.done += 1;
+        progress.current = Some(note.word.clone());
+        if !is_audio_ok(&note.audio_state) {
+            progress.failures += 1;
+        }
+        monitor.report(&progress);
+    }
+
     Ok(())
 }
 
-pub async fn fetch_audio(note: &mut Note, dest: &str) {
+/// Replaces the clip of a note with one from any source other than the one that produced it
+pub async fn refetch_audio(deck: &mut Deck, dest_dir: &str, note_id: u32) -> Result<()> {
+    let sources = AudioSources::from_config(&deck.audio_sources);
+    let audio_path = format!("{}/{}{}", dest_dir, deck.media_key(note_id), AUDIO_SUFFIX);
+    let note = deck.notes.iter_mut()
+        .find(|it| it.id == Some(note_id))
+        .ok_or_else(|| DeckError::NoteError(note_id, "not found".to_string()))?;
+
+    // A clip left over by an interrupted refetch would be taken for the new one
+    let tmp_path = format!("{}.tmp", audio_path);
+    if Path::new(&tmp_path).exists() {
+        fs::remove_file(&tmp_path).await
+            .with_context(|| format!("Failed to delete {}", tmp_path))?;
+    }
+    let prev_state = note.audio_state.clone();
+    let prev_source = note.audio_source.clone();
+    fetch_audio(&sources, note, &tmp_path, prev_source.as_deref()).await;
+
+    if !is_audio_ok(&note.audio_state) {
+        note.audio_state = prev_state;
+        note.audio_source = prev_source;
+        bail!(AudioError::UnavailableError(note.word.clone()));
+    }
+
+    fs::rename(&tmp_path, &audio_path).await
+        .with_context(|| format!("Failed to replace {}", audio_path))?;
+
+    Ok(())
+}
+
+pub async fn fetch_audio(sources: &AudioSources, note: &mut Note, dest: &str, skip: Option<&str>) {
     if Path::new(dest).exists() {
         return;
     }
 
-    let is_kanji = note.reading.is_some();
-    let kanji = if is_kanji { Some(note.word.as_str()) } else { None };
-    let kana = &note.reading.as_ref().unwrap_or(&note.word);
-    let res = audio::fetch_audio(&dest, kanji, kana).await;
+    let (kanji, kana) = note.audio_terms();
+    let res = sources.fetch(&dest, kanji.as_deref(), &kana, skip).await;
+    set_audio_result(note, res);
+}
+
+async fn fetch_with_backoff(
+    sources: &AudioSources,
+    dest: &str,
+    opt_kanji: Option<&str>,
+    kana: &str,
+    max_attempts: u32,
+    backoff: Duration,
+) -> Result<String> {
+    let mut attempt = 1;
+
+    loop {
+        match sources.fetch(dest, opt_kanji, kana, None).await {
+            Err(err) if !audio::is_unavailable(&err) && attempt < max_attempts => {
+                tokio::time::sleep(backoff * 2u32.pow(attempt - 1)).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
 
+fn set_audio_result(note: &mut Note, res: Result<String>) {
     match res {
-        Ok(_) => {
+        Ok(source) => {
             note.audio_state = Some(AudioState::OK.to_string());
+            note.audio_source = Some(source);
         },
         Err(err) => {
             if_chain! {
@@ -325,6 +1048,114 @@ pub async fn fetch_audio(note: &mut Note, dest: &str) {
     }
 }
 
+/// Renames media named after the note word or after the deck's current naming scheme to the
+/// separated scheme, pinning each note's GUID so re-imports keep updating the same Anki notes.
+pub async fn upgrade_media_naming(deck: &mut Deck, media_dir: &str) -> Result<()> {
+    upgrade_media_naming_with(deck, media_dir, &Monitor::silent()).await
+}
+
+/// Same as [`upgrade_media_naming`], reporting progress per note. The deck cannot be left
+/// half-migrated, so files renamed before a cancellation or an error are moved back.
+pub async fn upgrade_media_naming_with(deck: &mut Deck, media_dir: &str, monitor: &Monitor) -> Result<()> {
+    fs::create_dir_all(media_dir).await?;
+
+    let old_naming = deck.media_naming;
+    let new_naming = MediaNaming::Separated;
+    let mut renamed: Vec<(String, String)> = Vec::new();
+
+    if let Err(err) = rename_note_media(deck, media_dir, new_naming, monitor, &mut renamed).await {
+        for (src, dest) in renamed.iter().rev() {
+            fs::rename(dest, src).await
+                .with_context(|| format!("Failed to restore {}", src))?;
+        }
+        return Err(err);
+    }
+
+    for note in deck.notes.iter_mut() {
+        if let (None, Some(id)) = (&note.guid, note.id) {
+            note.guid = Some(old_naming.guid(deck.id, id));
+        }
+    }
+
+    deck.media_naming = new_naming;
+
+    Ok(())
+}
+
+/// Renames the media of every note to `new_naming`, recording each rename in `renamed`
+async fn rename_note_media(
+    deck: &Deck,
+    media_dir: &str,
+    new_naming: MediaNaming,
+    monitor: &Monitor,
+    renamed: &mut Vec<(String, String)>,
+) -> Result<()> {
+    let media = MediaIndex::load(media_dir).await?;
+    let old_naming = deck.media_naming;
+    let mut progress = Progress { total: deck.notes.len(), ..Default::default() };
+
+    for note in deck.notes.iter() {
+        if monitor.is_cancelled() {
+            bail!(TaskError::CancelledError);
+        }
+
+        let id = note.id.with_context(|| "Note ID must be defined")?;
+        let dest = format!("{}/{}", media_dir, new_naming.media_key(deck.id, id));
+
+        rename_media(&media, &note.word, &dest, renamed).await?;
+        rename_file(&format!("{}/{}_r.mp3", media_dir, note.word), &format!("{}{}", dest, AUDIO_SUFFIX), renamed).await?;
+
+        if old_naming != new_naming {
+            let key = old_naming.media_key(deck.id, id);
+            let src = media.path(&key);
+            rename_media(&media, &key, &dest, renamed).await?;
+            rename_file(&format!("{}{}", src, AUDIO_SUFFIX), &format!("{}{}", dest, AUDIO_SUFFIX), renamed).await?;
+        }
+
+        progress.done += 1;
+        progress.current = Some(note.word.clone());
+        monitor.report(&progress);
+    }
+
+    Ok(())
+}
+
+/// Renames the media files of `key` in the index, keeping their extensions
+async fn rename_media(media: &MediaIndex, key: &str, dest_base: &str, renamed: &mut Vec<(String, String)>) -> Result<()> {
+    for name in media.media(key) {
+        let ext = &name[key.len()..];
+        rename_file(&media.path(name), &format!("{}{}", dest_base, ext), renamed).await?;
+    }
+    Ok(())
+}
+
+async fn rename_file(src: &str, dest: &str, renamed: &mut Vec<(String, String)>) -> Result<()> {
+    if !Path::new(src).exists() {
+        return Ok(());
+    }
+    if Path::new(dest).exists() {
+        bail!(DeckError::FileExistsError(dest.to_string()));
+    }
+    fs::rename(src, dest).await
+        .with_context(|| format!("Failed to rename {} to {}", src, dest))?;
+    renamed.push((src.to_string(), dest.to_string()));
+    Ok(())
+}
+
+/// Anki tags cannot contain spaces, they are replaced with underscores. Blank tags give `None`.
+pub fn normalize_tag(tag: &str) -> Option<String> {
+    let tag = tag.split_whitespace().collect::<Vec<&str>>().join("_");
+    Some(tag).filter(|it| !it.is_empty())
+}
+
+fn has_tag(tags: &[String], tag: &str) -> bool {
+    tags.iter().any(|it| it.to_lowercase() == tag.to_lowercase())
+}
+
+fn alignment_warning(id: u32, note: &Note, warning: &str) -> String {
+    format!("Note {} ({}): {}, the reading is shown over the whole word", id, note.word, warning)
+}
+
 pub fn is_valid_extension(ext: &str) -> bool {
     is_extension_in(ext, &MEDIA_EXT)
 }
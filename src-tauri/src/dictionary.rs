@@ -0,0 +1,277 @@
+use anyhow::{bail, Context, Result};
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::deck::DeckError;
+use crate::ext::char::CharExt;
+use crate::progress::{Monitor, Progress, TaskError};
+
+/// Maximum number of entries returned by a lookup
+const MAX_RESULTS: usize = 20;
+
+/// Reading of an entry, `restrictions` lists the spellings it applies to (all when empty)
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EntryReading {
+    pub kana: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub restrictions: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_kanji: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Sense {
+    /// JMdict part-of-speech codes, like `n` or `v5r`
+    pub pos: Vec<String>,
+    pub glosses: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DictionaryEntry {
+    pub id: u32,
+    pub kanji: Vec<String>,
+    pub readings: Vec<EntryReading>,
+    pub senses: Vec<Sense>,
+}
+
+/// JMdict entries stored in SQLite and indexed by spelling and reading
+pub struct Dictionary {
+    conn: Connection,
+}
+
+enum Element {
+    EntrySeq,
+    Keb,
+    Reb,
+    ReRestr,
+    Pos,
+    Gloss,
+    Other,
+}
+
+impl Dictionary {
+    pub fn open(path: &str) -> Result<Dictionary> {
+        let conn = Connection::open(path).with_context(|| format!("Cannot open dictionary {}", path))?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS entries (id INTEGER PRIMARY KEY, data TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS keys (key TEXT NOT NULL, entry INTEGER NOT NULL);
+             CREATE INDEX IF NOT EXISTS keys_key ON keys (key);",
+        )?;
+
+        Ok(Dictionary { conn })
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        let count: i64 = self.conn.query_row("SELECT COUNT(*) FROM entries", [], |row| row.get(0))?;
+        Ok(count == 0)
+    }
+
+    /// Replaces the dictionary with the entries of a JMdict XML file, returns the number of entries
+    pub fn import_file(&mut self, src: &str, monitor: &Monitor) -> Result<usize> {
+        let file = File::open(src).with_context(|| DeckError::NotFoundError(src.to_string()))?;
+        let total = file.metadata().map(|it| it.len() as usize).unwrap_or_default();
+
+        self.import(BufReader::new(file), total, monitor)
+            .with_context(|| format!("Failed to import {}", src))
+    }
+
+    /// Replaces the dictionary with the entries of a JMdict document. Progress is
+    /// reported in bytes read out of `total`. Nothing changes if cancelled.
+    pub fn import<R: BufRead>(&mut self, reader: R, total: usize, monitor: &Monitor) -> Result<usize> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM entries", [])?;
+        tx.execute("DELETE FROM keys", [])?;
+
+        let mut reader = Reader::from_reader(reader);
+        let mut buf = Vec::new();
+        let mut entry = DictionaryEntry::default();
+        let mut element = Element::Other;
+        let mut text = String::new();
+        let mut english = true;
+        let mut count = 0;
+        let mut progress = Progress { total, ..Default::default() };
+
+        {
+            let mut insert_entry = tx.prepare("INSERT OR REPLACE INTO entries (id, data) VALUES (?1, ?2)")?;
+            let mut insert_key = tx.prepare("INSERT INTO keys (key, entry) VALUES (?1, ?2)")?;
+
+            loop {
+                match reader.read_event_into(&mut buf)? {
+                    Event::Start(e) => {
+                        text.clear();
+                        element = match e.name().as_ref() {
+                            b"entry" => {
+                                entry = DictionaryEntry::default();
+                                Element::Other
+                            },
+                            b"ent_seq" => Element::EntrySeq,
+                            b"keb" => Element::Keb,
+                            b"reb" => {
+                                entry.readings.push(EntryReading::default());
+                                Element::Reb
+                            },
+                            b"re_restr" => Element::ReRestr,
+                            b"sense" => {
+                                entry.senses.push(Sense::default());
+                                Element::Other
+                            },
+                            b"pos" => Element::Pos,
+                            b"gloss" => {
+                                english = match e.try_get_attribute("xml:lang")? {
+                                    Some(lang) => lang.value.as_ref() == b"eng",
+                                    None => true,
+                                };
+                                Element::Gloss
+                            },
+                            _ => Element::Other,
+                        };
+                    },
+                    Event::Empty(e) => {
+                        if let (b"re_nokanji", Some(reading)) = (e.name().as_ref(), entry.readings.last_mut()) {
+                            reading.no_kanji = true;
+                        }
+                    },
+                    Event::Text(e) => text.push_str(&e.decode()?),
+                    // Part-of-speech codes are entities like `&n;`, kept as their name
+                    Event::GeneralRef(e) => {
+                        let name = e.decode()?;
+                        text.push_str(resolve_predefined_entity(&name).unwrap_or(&name));
+                    },
+                    Event::End(e) => {
+                        let value = text.trim().to_string();
+
+                        match element {
+                            Element::EntrySeq => entry.id = value.parse().with_context(|| format!("Invalid ent_seq {}", value))?,
+                            Element::Keb => entry.kanji.push(value),
+                            Element::Reb => {
+                                if let Some(reading) = entry.readings.last_mut() {
+                                    reading.kana = value;
+                                }
+                            },
+                            Element::ReRestr => {
+                                if let Some(reading) = entry.readings.last_mut() {
+                                    reading.restrictions.push(value);
+                                }
+                            },
+                            Element::Pos => {
+                                if let Some(sense) = entry.senses.last_mut() {
+                                    sense.pos.push(value);
+                                }
+                            },
+                            Element::Gloss if english => {
+                                if let Some(sense) = entry.senses.last_mut() {
+                                    sense.glosses.push(value);
+                                }
+                            },
+                            _ => {},
+                        }
+
+                        element = Element::Other;
+                        text.clear();
+
+                        if e.name().as_ref() == b"entry" {
+                            finish_entry(&mut entry);
+
+                            insert_entry.execute(params![entry.id, serde_json::to_string(&entry)?])?;
+
+                            let keys = entry.kanji.iter()
+                                .cloned()
+                                .chain(entry.readings.iter().map(|it| normalize_key(&it.kana)));
+
+                            for key in keys {
+                                insert_key.execute(params![key, entry.id])?;
+                            }
+
+                            count += 1;
+
+                            if count % 1000 == 0 {
+                                if monitor.is_cancelled() {
+                                    bail!(TaskError::CancelledError);
+                                }
+                                progress.done = reader.buffer_position() as usize;
+                                progress.current = entry.kanji.first().or_else(|| entry.readings.first().map(|it| &it.kana)).cloned();
+                                monitor.report(&progress);
+                            }
+                        }
+                    },
+                    Event::Eof => break,
+                    _ => {},
+                }
+                buf.clear();
+            }
+        }
+
+        if count == 0 {
+            bail!("No JMdict entries found");
+        }
+
+        tx.commit()?;
+
+        progress.done = progress.total;
+        monitor.report(&progress);
+
+        Ok(count)
+    }
+
+    /// Finds the entries written or read as `word`. Readings restricted to other spellings are left out.
+    pub fn lookup(&self, word: &str) -> Result<Vec<DictionaryEntry>> {
+        let word = word.trim();
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT e.data FROM keys k JOIN entries e ON e.id = k.entry
+             WHERE k.key = ?1 ORDER BY e.id LIMIT ?2",
+        )?;
+
+        let rows = stmt
+            .query_map(params![normalize_key(word), MAX_RESULTS as i64], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        let mut entries = Vec::new();
+
+        for data in rows {
+            let mut entry: DictionaryEntry = serde_json::from_str(&data)?;
+
+            if entry.kanji.iter().any(|it| it == word) {
+                entry.readings.retain(|it| {
+                    !it.no_kanji && (it.restrictions.is_empty() || it.restrictions.iter().any(|r| r == word))
+                });
+            }
+
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Senses without part of speech use the one of the previous sense
+fn finish_entry(entry: &mut DictionaryEntry) {
+    let mut pos: Vec<String> = Vec::new();
+
+    for sense in entry.senses.iter_mut() {
+        if sense.pos.is_empty() {
+            sense.pos = pos.clone();
+        } else {
+            pos = sense.pos.clone();
+        }
+    }
+
+    entry.senses.retain(|it| !it.glosses.is_empty());
+}
+
+/// Kana keys are stored in hiragana so that katakana spellings match either way
+fn normalize_key(key: &str) -> String {
+    if key.chars().all(|c| c.is_kana()) {
+        key.chars().map(|c| c.to_hiragana()).collect()
+    } else {
+        key.to_string()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,108 @@
+use serde::Serialize;
+use std::io;
+
+use crate::audio::AudioError;
+use crate::deck::DeckError;
+use crate::progress::TaskError;
+use crate::spreadsheet::TableError;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorKind {
+    NotFound,
+    DirectoryMissing,
+    TemplateMissing,
+    InvalidTemplate,
+    AlreadyExists,
+    AudioUnavailable,
+    InvalidDeck,
+    InvalidNote,
+    InvalidMedia,
+    InvalidTable,
+    Cancelled,
+    Io,
+    Other,
+}
+
+/// Error returned to the frontend by every command
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandError {
+    pub kind: ErrorKind,
+    pub path: Option<String>,
+    pub note_id: Option<u32>,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> CommandError {
+        CommandError { kind, path: None, note_id: None, message: message.into() }
+    }
+
+    pub fn with_path(mut self, path: &str) -> CommandError {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn with_note_id(mut self, note_id: u32) -> CommandError {
+        self.note_id = Some(note_id);
+        self
+    }
+}
+
+impl From<DeckError> for CommandError {
+    fn from(err: DeckError) -> Self {
+        CommandError::from(anyhow::Error::from(err))
+    }
+}
+
+impl From<anyhow::Error> for CommandError {
+    fn from(err: anyhow::Error) -> Self {
+        let message = format!("{:#}", err);
+
+        if let Some(deck_err) = err.downcast_ref::<DeckError>() {
+            return match deck_err {
+                DeckError::DuplicateIdError(id) => CommandError::new(ErrorKind::InvalidDeck, message).with_note_id(*id),
+                DeckError::IdOverflowError => CommandError::new(ErrorKind::InvalidDeck, message),
+                DeckError::ParseError(_) => CommandError::new(ErrorKind::InvalidDeck, message),
+                DeckError::NotFoundError(path) => CommandError::new(ErrorKind::NotFound, message).with_path(path),
+                DeckError::DirectoryError(path) => CommandError::new(ErrorKind::DirectoryMissing, message).with_path(path),
+                DeckError::TemplateError(path) => CommandError::new(ErrorKind::TemplateMissing, message).with_path(path),
+                DeckError::FileExistsError(path) => CommandError::new(ErrorKind::AlreadyExists, message).with_path(path),
+                DeckError::MediaError(path) => CommandError::new(ErrorKind::InvalidMedia, message).with_path(path),
+                DeckError::MediaConflictError(path) => CommandError::new(ErrorKind::InvalidMedia, message).with_path(path),
+                DeckError::DuplicateDeckIdError(..) => CommandError::new(ErrorKind::InvalidDeck, message),
+                DeckError::NoteError(id, _) => CommandError::new(ErrorKind::InvalidNote, message).with_note_id(*id),
+                DeckError::FieldError(_) => CommandError::new(ErrorKind::InvalidDeck, message),
+                DeckError::InvalidTemplateError(_) => CommandError::new(ErrorKind::InvalidTemplate, message),
+            };
+        }
+
+        if let Some(audio_err) = err.downcast_ref::<AudioError>() {
+            return match audio_err {
+                AudioError::KanaError => CommandError::new(ErrorKind::InvalidNote, message),
+                AudioError::UnavailableError(_) => CommandError::new(ErrorKind::AudioUnavailable, message),
+                AudioError::PathError(path) => CommandError::new(ErrorKind::DirectoryMissing, message).with_path(path),
+                AudioError::ServerError(_) => CommandError::new(ErrorKind::Other, message),
+            };
+        }
+
+        if err.downcast_ref::<TableError>().is_some() {
+            return CommandError::new(ErrorKind::InvalidTable, message);
+        }
+
+        if let Some(TaskError::CancelledError) = err.downcast_ref::<TaskError>() {
+            return CommandError::new(ErrorKind::Cancelled, message);
+        }
+
+        if let Some(io_err) = err.root_cause().downcast_ref::<io::Error>() {
+            return match io_err.kind() {
+                io::ErrorKind::NotFound => CommandError::new(ErrorKind::NotFound, message),
+                io::ErrorKind::AlreadyExists => CommandError::new(ErrorKind::AlreadyExists, message),
+                _ => CommandError::new(ErrorKind::Io, message),
+            };
+        }
+
+        CommandError::new(ErrorKind::Other, message)
+    }
+}
@@ -1,16 +1,81 @@
 use std::ops::RangeInclusive;
 
-static KANJI_RANGE: RangeInclusive<char> = '\u{4E00}' ..= '\u{9FAF}';
+static KANJI_RANGES: [RangeInclusive<char>; 5] = [
+    '\u{4E00}' ..= '\u{9FFF}',   // CJK Unified Ideographs
+    '\u{3400}' ..= '\u{4DBF}',   // Extension A
+    '\u{20000}' ..= '\u{3134F}', // Extensions B to G
+    '\u{F900}' ..= '\u{FAFF}',   // Compatibility Ideographs
+    '\u{2F800}' ..= '\u{2FA1F}', // Compatibility Ideographs Supplement
+];
+
+static HIRAGANA_RANGE: RangeInclusive<char> = '\u{3041}' ..= '\u{309F}';
+
+static KATAKANA_RANGES: [RangeInclusive<char>; 3] = [
+    '\u{30A0}' ..= '\u{30FF}',
+    '\u{31F0}' ..= '\u{31FF}', // Phonetic Extensions
+    '\u{FF66}' ..= '\u{FF9F}', // Halfwidth
+];
+
+static PUNCTUATION_RANGES: [RangeInclusive<char>; 7] = [
+    '\u{3000}' ..= '\u{3004}',
+    '\u{3008}' ..= '\u{303A}',
+    '\u{303C}' ..= '\u{303F}',
+    '\u{FF01}' ..= '\u{FF0F}',
+    '\u{FF1A}' ..= '\u{FF20}',
+    '\u{FF3B}' ..= '\u{FF40}',
+    '\u{FF5B}' ..= '\u{FF65}',
+];
+
+/// Kanji iteration marks and the kana ones (ゝゞヽヾ)
+static ITERATION_MARKS: [char; 6] = ['々', '〻', 'ゝ', 'ゞ', 'ヽ', 'ヾ'];
+
+/// Characters read like a kanji within a word, as in 一ヶ月 or 〆切
+static KANJI_LIKE: [char; 5] = ['々', '〻', '〆', 'ヶ', 'ヵ'];
 
 pub trait CharExt {
     fn is_kanji(&self) -> bool;
+    fn is_hiragana(&self) -> bool;
+    fn is_katakana(&self) -> bool;
+    fn is_kana(&self) -> bool;
+    fn is_iteration_mark(&self) -> bool;
+    fn is_japanese_punctuation(&self) -> bool;
+    /// Whether the character takes furigana: kanji, 々, 〆 and counters like ヶ
+    fn is_ruby_base(&self) -> bool;
     fn is_open_ruby_parenthesis(&self) -> bool;
     fn is_closed_ruby_parenthesis(&self) -> bool;
+    /// Converts full-width katakana to hiragana, other characters are returned as is
+    fn to_hiragana(&self) -> char;
+    /// Converts hiragana to katakana, other characters are returned as is
+    fn to_katakana(&self) -> char;
 }
 
 impl CharExt for char {
     fn is_kanji(&self) -> bool {
-       KANJI_RANGE.contains(self)
+        *self == '〇' || KANJI_RANGES.iter().any(|it| it.contains(self))
+    }
+
+    fn is_hiragana(&self) -> bool {
+        HIRAGANA_RANGE.contains(self)
+    }
+
+    fn is_katakana(&self) -> bool {
+        *self != '・' && KATAKANA_RANGES.iter().any(|it| it.contains(self))
+    }
+
+    fn is_kana(&self) -> bool {
+        self.is_hiragana() || self.is_katakana()
+    }
+
+    fn is_iteration_mark(&self) -> bool {
+        ITERATION_MARKS.contains(self)
+    }
+
+    fn is_japanese_punctuation(&self) -> bool {
+        *self == '・' || PUNCTUATION_RANGES.iter().any(|it| it.contains(self))
+    }
+
+    fn is_ruby_base(&self) -> bool {
+        self.is_kanji() || KANJI_LIKE.contains(self)
     }
 
     fn is_open_ruby_parenthesis(&self) -> bool {
@@ -20,4 +85,18 @@ impl CharExt for char {
     fn is_closed_ruby_parenthesis(&self) -> bool {
         *self == '」' || *self == ']'
     }
+
+    fn to_hiragana(&self) -> char {
+        match self {
+            'ァ' ..= 'ヶ' | 'ヽ' | 'ヾ' => char::from_u32(*self as u32 - 0x60).unwrap_or(*self),
+            _ => *self,
+        }
+    }
+
+    fn to_katakana(&self) -> char {
+        match self {
+            'ぁ' ..= 'ゖ' | 'ゝ' | 'ゞ' => char::from_u32(*self as u32 + 0x60).unwrap_or(*self),
+            _ => *self,
+        }
+    }
 }
\ No newline at end of file
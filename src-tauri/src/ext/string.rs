@@ -7,8 +7,15 @@ pub trait StringExt {
     fn contains_ruby(&self) -> bool;
     fn remove_ruby(&self) -> String;
     fn format_ruby(&self) -> String;
+    fn ruby_reading(&self) -> String;
+    fn normalize_width(&self) -> String;
+    fn to_hiragana(&self) -> String;
+    fn to_katakana(&self) -> String;
 }
 
+/// Full-width equivalents of U+FF65 to U+FF9D
+static HALF_WIDTH_KATAKANA: &str = "・ヲァィゥェォャュョッーアイウエオカキクケコサシスセソタチツテトナニヌネノハヒフヘホマミムメモヤユヨラリルレロワン";
+
 impl StringExt for String {
     fn extract(&self, start_delim: &str, end_delim: Option<&str>) -> Result<&str> {
         let start_index = self
@@ -65,7 +72,7 @@ impl StringExt for String {
         let mut separate = true;
 
         for c in self.chars() {
-            if separate && c.is_kanji() {
+            if separate && c.is_ruby_base() {
                 buf.push(' ');
                 separate = false;
             } else if c.is_closed_ruby_parenthesis() {
@@ -84,6 +91,72 @@ impl StringExt for String {
 
         buf
     }
+
+    fn ruby_reading(&self) -> String {
+        let mut buf: Vec<char> = Vec::new();
+        let mut open = false;
+
+        for c in self.chars() {
+            if c.is_open_ruby_parenthesis() {
+                while buf.last().map_or(false, |it| it.is_ruby_base()) {
+                    buf.pop();
+                }
+                open = true;
+            } else if c.is_closed_ruby_parenthesis() {
+                open = false;
+            } else if c != ' ' || open {
+                buf.push(c);
+            }
+        }
+
+        buf.iter().collect()
+    }
+
+    fn to_hiragana(&self) -> String {
+        self.normalize_width().chars().map(|c| c.to_hiragana()).collect()
+    }
+
+    fn to_katakana(&self) -> String {
+        self.normalize_width().chars().map(|c| c.to_katakana()).collect()
+    }
+
+    /// Converts half-width katakana to full width, combining voicing marks,
+    /// and full-width ASCII to ASCII
+    fn normalize_width(&self) -> String {
+        let mut buf = String::new();
+
+        for c in self.chars() {
+            match c {
+                '\u{FF01}' ..= '\u{FF5E}' => buf.push(char::from_u32(c as u32 - 0xFEE0).unwrap_or(c)),
+                '\u{FF65}' ..= '\u{FF9D}' => {
+                    buf.push(HALF_WIDTH_KATAKANA.chars().nth(c as usize - 0xFF65).unwrap_or(c));
+                },
+                '\u{FF9E}' | '\u{FF9F}' => {
+                    match buf.pop().map(|prev| (prev, with_voicing_mark(prev, c))) {
+                        Some((_, Some(voiced))) => buf.push(voiced),
+                        Some((prev, None)) => {
+                            buf.push(prev);
+                            buf.push(if c == '\u{FF9E}' { '゛' } else { '゜' });
+                        },
+                        None => buf.push(if c == '\u{FF9E}' { '゛' } else { '゜' }),
+                    }
+                },
+                _ => buf.push(c),
+            }
+        }
+
+        buf
+    }
+}
+
+fn with_voicing_mark(c: char, mark: char) -> Option<char> {
+    let offset = match (c, mark) {
+        ('ウ', '\u{FF9E}') => return Some('ヴ'),
+        (_, '\u{FF9E}') if "カキクケコサシスセソタチツテトハヒフヘホ".contains(c) => 1,
+        (_, '\u{FF9F}') if "ハヒフヘホ".contains(c) => 2,
+        _ => return None,
+    };
+    char::from_u32(c as u32 + offset)
 }
 
 fn tag_err(tag: &str) -> String {
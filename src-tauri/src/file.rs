@@ -1,18 +1,24 @@
 use std::path::Path;
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-pub fn find_extension<'a>(partial_path: &str, extensions: &Vec<&'a str>) -> Option<&'a str> {
-    for ext in extensions {
-        let filename = format!("{}.{}", partial_path, ext);
-        let path = Path::new(&filename);
-        if path.exists() {
-            return Some(ext);
-        }
-    }
+/// Writes to a temporary file next to `path` and renames it over `path`,
+/// so a crash mid-write never leaves a truncated file behind
+pub async fn write_atomic(path: &str, contents: impl AsRef<[u8]>) -> Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+
+    let mut file = fs::File::create(&tmp_path).await
+        .with_context(|| format!("Cannot create {}", tmp_path))?;
+    file.write_all(contents.as_ref()).await?;
+    file.sync_all().await?;
+    drop(file);
 
-    None
+    fs::rename(&tmp_path, path).await
+        .with_context(|| format!("Cannot replace {}", path))?;
+
+    Ok(())
 }
 
 pub async fn create_parent_dir(path: &Path) -> Result<()> {
@@ -20,4 +26,69 @@ pub async fn create_parent_dir(path: &Path) -> Result<()> {
         fs::create_dir_all(parent).await?;
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+pub fn deck_path(dir: &str) -> String {
+    format!("{}/deck.json", dir)
+}
+
+pub fn backup_path(dir: &str) -> String {
+    format!("{}/deck.json.bk", dir)
+}
+
+pub fn backups_path(dir: &str) -> String {
+    format!("{}/backups", dir)
+}
+
+pub fn media_path(dir: &str) -> String {
+    format!("{}/media", dir)
+}
+
+pub fn static_path(dir: &str) -> String {
+    format!("{}/static", dir)
+}
+
+pub fn front_path(dir: &str) -> String {
+    format!("{}/front.html", dir)
+}
+
+pub fn back_path(dir: &str) -> String {
+    format!("{}/back.html", dir)
+}
+
+pub fn css_path(dir: &str) -> String {
+    format!("{}/style.css", dir)
+}
+
+/// Card type read from `front.html` and `back.html`
+pub const DEFAULT_CARD: &str = "Default";
+
+pub fn card_front_path(dir: &str, card: &str) -> String {
+    if card == DEFAULT_CARD { front_path(dir) } else { format!("{}/{}.front.html", dir, card) }
+}
+
+pub fn card_back_path(dir: &str, card: &str) -> String {
+    if card == DEFAULT_CARD { back_path(dir) } else { format!("{}/{}.back.html", dir, card) }
+}
+
+/// Card types of a template directory: `Default` when `front.html` exists,
+/// then one per `<name>.front.html` in alphabetical order
+pub fn card_names(dir: &str) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().to_str()?.strip_suffix(".front.html").map(|it| it.to_string()))
+                .filter(|name| !name.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    names.sort();
+
+    if Path::new(&front_path(dir)).exists() {
+        names.insert(0, DEFAULT_CARD.to_string());
+    }
+
+    names
+}
@@ -1,16 +1,81 @@
-use anyhow::{Result, Context};
+use anyhow::{bail, Result, Context};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 
 use crate::ext::char::CharExt;
+use crate::ext::string::StringExt;
+use crate::kanjidic::KanjiReadings;
+
+static MISMATCH: &str = "Kanji and Kana sentences do not match";
+static VOWELS: [char; 5] = ['あ', 'い', 'う', 'え', 'お'];
+static UNVOICED: &str = "かきくけこさしすせそたちつてとはひふへほ";
+static VOICED: &str = "がぎぐげござじずぜぞだぢづでどばびぶべぼ";
+static COUNTER_READINGS: [&str; 4] = ["か", "が", "こ", "け"];
+
+/// A run of text and, for kanji, its reading
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FuriganaSegment {
+    pub base: String,
+    pub reading: Option<String>,
+}
+
+/// How furigana is written into the Reading field of a package
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum RubyStyle {
+    /// `漢字[かんじ]`, rendered by `{{furigana:Reading}}`
+    Anki,
+    /// `<ruby>漢字<rt>かんじ</rt></ruby>`
+    Html,
+    /// `｜漢字《かんじ》`
+    Aozora,
+    /// `かんじ`
+    Kana,
+}
+
+impl Default for RubyStyle {
+    fn default() -> Self {
+        RubyStyle::Anki
+    }
+}
+
+impl RubyStyle {
+    pub fn render(&self, segments: &[FuriganaSegment]) -> String {
+        let mut s = String::new();
+
+        for (i, segment) in segments.iter().enumerate() {
+            match (self, &segment.reading) {
+                (RubyStyle::Anki, Some(reading)) => {
+                    if i != 0 {
+                        s.push(' ');
+                    }
+                    s.push_str(&format!("{}[{}]", segment.base, reading));
+                },
+                (RubyStyle::Html, Some(reading)) => {
+                    s.push_str(&format!("<ruby>{}<rt>{}</rt></ruby>", segment.base, reading));
+                },
+                (RubyStyle::Aozora, Some(reading)) => {
+                    s.push_str(&format!("｜{}《{}》", segment.base, reading));
+                },
+                (RubyStyle::Kana, Some(reading)) => s.push_str(reading),
+                (_, None) => s.push_str(&segment.base),
+            }
+        }
+
+        s
+    }
+}
 
 /* Adapted from: https://github.com/zacharied/autofurigana */
-pub fn generate_furigana(kanji_str: &str, kana_str: &str) -> Result<Vec<Vec<String>>> {
-    let mut pairs: Vec<Vec<String>> = Vec::new();
+pub fn generate_furigana(kanji_str: &str, kana_str: &str) -> Result<Vec<FuriganaSegment>> {
+    let mut pairs: Vec<FuriganaSegment> = Vec::new();
 
     let mut kanji_buf: Vec<char> = Vec::new();
     let mut kana_buf: Vec<char> = Vec::new();
 
-    let kanji_vec: Vec<char> = kanji_str.chars().collect();
-    let kana_vec: Vec<char> = kana_str.chars().collect();
+    let kanji_vec: Vec<char> = kanji_str.to_string().normalize_width().chars().collect();
+    let kana_vec: Vec<char> = kana_str.to_string().normalize_width().chars().collect();
 
     let mut j = 0;
 
@@ -18,27 +83,35 @@ pub fn generate_furigana(kanji_str: &str, kana_str: &str) -> Result<Vec<Vec<Stri
         kanji_buf.push(kanji_vec[i]);
 
         if i == kanji_vec.len() - 1 {
-            if kanji_vec[i].is_kanji() {
+            if kanji_vec[i].is_ruby_base() {
                 while j < kana_vec.len() {
                     kana_buf.push(kana_vec[j]);
                     j += 1;
                 }
+                if kana_buf.is_empty() {
+                    bail!(MISMATCH);
+                }
             } else {
+                match_kana(&kanji_buf, &kana_vec, j)?;
+                if j + kanji_buf.len() != kana_vec.len() {
+                    bail!(MISMATCH);
+                }
                 kana_buf.clear();
             }
             add_pair(&mut pairs, &mut kanji_buf, &mut kana_buf);
-        } else if kanji_vec[i].is_kanji() && !kanji_vec[i + 1].is_kanji() {
+        } else if kanji_vec[i].is_ruby_base() && !kanji_vec[i + 1].is_ruby_base() {
             loop {
-                let kana = kana_vec.get(j).with_context(|| "Kanji and Kana sentences do not match")?;
-                if *kana == kanji_vec[i + 1] && kana_buf.len() >= kanji_buf.len() {
+                let kana = kana_vec.get(j).with_context(|| MISMATCH)?;
+                if kana_matches(*kana, kanji_vec[i + 1]) && kana_buf.len() >= kanji_buf.len() {
                     break;
                 }
                 kana_buf.push(*kana);
                 j += 1;
             }
             add_pair(&mut pairs, &mut kanji_buf, &mut kana_buf);
-        } else if !kanji_vec[i].is_kanji() && kanji_vec[i + 1].is_kanji() {
+        } else if !kanji_vec[i].is_ruby_base() && kanji_vec[i + 1].is_ruby_base() {
             kana_buf.clear();
+            match_kana(&kanji_buf, &kana_vec, j)?;
             j += kanji_buf.len();
             add_pair(&mut pairs, &mut kanji_buf, &mut kana_buf);
         }
@@ -47,26 +120,223 @@ pub fn generate_furigana(kanji_str: &str, kana_str: &str) -> Result<Vec<Vec<Stri
     Ok(pairs)
 }
 
+/// Splits the reading according to the readings of each kanji, falling back to
+/// `generate_furigana` when some kanji has no matching reading, as in 今日 (きょう)
+pub fn generate_furigana_with(
+    kanji_str: &str,
+    kana_str: &str,
+    readings: Option<&KanjiReadings>,
+) -> Result<Vec<FuriganaSegment>> {
+    if let Some(segments) = readings.and_then(|it| dictionary_furigana(kanji_str, kana_str, it)) {
+        return Ok(segments);
+    }
+    generate_furigana(kanji_str, kana_str)
+}
+
+/// Like `generate_furigana`, but a reading that cannot be aligned becomes a single ruby
+/// over the whole word. The alignment error is returned alongside as a warning. An empty
+/// reading gives the word without ruby.
+pub fn align_furigana(kanji_str: &str, kana_str: &str) -> (Vec<FuriganaSegment>, Option<String>) {
+    align_furigana_with(kanji_str, kana_str, None)
+}
+
+/// Like `align_furigana`, trying the segmentation matching the known readings of each kanji first
+pub fn align_furigana_with(
+    kanji_str: &str,
+    kana_str: &str,
+    readings: Option<&KanjiReadings>,
+) -> (Vec<FuriganaSegment>, Option<String>) {
+    let word = kanji_str.to_string().normalize_width();
+    let kana = kana_str.to_string().normalize_width();
+
+    if kana.trim().is_empty() {
+        return (vec![FuriganaSegment { base: word, reading: None }], None);
+    }
+
+    match generate_furigana_with(kanji_str, kana_str, readings) {
+        Ok(segments) => (segments, None),
+        Err(err) => (vec![FuriganaSegment { base: word, reading: Some(kana) }], Some(format!("{:#}", err))),
+    }
+}
+
 pub fn rubify(kanji_str: &str, kana_str: &str) -> Result<String> {
-    let pairs = generate_furigana(kanji_str, kana_str)?;
-    let mut s = String::new();
+    Ok(RubyStyle::Anki.render(&generate_furigana(kanji_str, kana_str)?))
+}
+
+/// Splits a word written with Anki ruby, like `今日[きょう]は 本当[ほんとう]`, into segments
+pub fn parse_ruby(s: &str) -> Vec<FuriganaSegment> {
+    let mut segments = Vec::new();
+    let mut base = String::new();
+    let mut reading: Option<String> = None;
+
+    for c in s.to_string().format_ruby().chars() {
+        match (c, reading.as_mut()) {
+            ('[', None) => reading = Some(String::new()),
+            (']', Some(_)) => {
+                segments.push(FuriganaSegment { base: base.clone(), reading: reading.take() });
+                base.clear();
+            },
+            (_, Some(reading)) => reading.push(c),
+            (' ', None) => {
+                if !base.is_empty() {
+                    segments.push(FuriganaSegment { base: base.clone(), reading: None });
+                    base.clear();
+                }
+            },
+            (_, None) => base.push(c),
+        }
+    }
+
+    if !base.is_empty() {
+        segments.push(FuriganaSegment { base, reading: None });
+    }
+
+    segments
+}
+
+fn dictionary_furigana(kanji_str: &str, kana_str: &str, readings: &KanjiReadings) -> Option<Vec<FuriganaSegment>> {
+    let word: Vec<char> = kanji_str.to_string().normalize_width().chars().collect();
+    let kana: Vec<char> = kana_str.to_string().normalize_width().chars().collect();
+
+    if !word.iter().any(|c| c.is_ruby_base()) {
+        return None;
+    }
+
+    let mut lengths = Vec::new();
+
+    if !match_readings(&word, &kana, readings, &mut lengths) {
+        return None;
+    }
+
+    let mut pairs: Vec<FuriganaSegment> = Vec::new();
+    let (mut kanji_buf, mut kana_buf): (Vec<char>, Vec<char>) = (Vec::new(), Vec::new());
+    let mut j = 0;
+
+    for (i, c) in word.iter().enumerate() {
+        if i > 0 && c.is_ruby_base() != word[i - 1].is_ruby_base() {
+            add_pair(&mut pairs, &mut kanji_buf, &mut kana_buf);
+        }
+        kanji_buf.push(*c);
+        if c.is_ruby_base() {
+            kana_buf.extend_from_slice(&kana[j..j + lengths[i]]);
+        }
+        j += lengths[i];
+    }
+
+    add_pair(&mut pairs, &mut kanji_buf, &mut kana_buf);
+    Some(pairs)
+}
+
+/// Depth-first search for a reading of every character that spells out `kana` exactly.
+/// `lengths` receives the number of kana read by each character.
+fn match_readings(word: &[char], kana: &[char], readings: &KanjiReadings, lengths: &mut Vec<usize>) -> bool {
+    let (i, j) = (lengths.len(), lengths.iter().sum::<usize>());
+
+    let c = match word.get(i) {
+        Some(c) => *c,
+        None => return j == kana.len(),
+    };
+
+    if !c.is_ruby_base() {
+        if kana.get(j).map_or(false, |it| kana_matches(c, *it)) {
+            lengths.push(1);
+            if match_readings(word, kana, readings, lengths) {
+                return true;
+            }
+            lengths.pop();
+        }
+        return false;
+    }
+
+    for candidate in reading_candidates(word, i, readings) {
+        let len = candidate.len();
+        let matches = kana.get(j..j + len).map_or(false, |slice| {
+            slice.iter().zip(candidate.iter()).all(|(a, b)| kana_matches(*a, *b))
+        });
 
-    for i in 0..pairs.len() {
-        if !pairs[i][1].is_empty() {
-            if i != 0 {
-                s.push(' ');
+        if matches {
+            lengths.push(len);
+            if match_readings(word, kana, readings, lengths) {
+                return true;
             }
-            s.push_str(&format!("{}[{}]", pairs[i][0], pairs[i][1]));
-        } else {
-            s.push_str(&pairs[i][0]);
+            lengths.pop();
         }
     }
 
-    Ok(s)
+    false
+}
+
+/// Readings of `word[i]` with their sound changes: voiced first kana (rendaku)
+/// and a final small っ (sokuon). 々 repeats the reading of the previous kanji.
+fn reading_candidates(word: &[char], i: usize, readings: &KanjiReadings) -> Vec<Vec<char>> {
+    let base: Vec<&str> = match word[i] {
+        '々' | '〻' if i > 0 => readings.get(word[i - 1]).iter().map(|it| it.as_str()).collect(),
+        'ヶ' | 'ヵ' => COUNTER_READINGS.to_vec(),
+        '〆' => vec!["しめ"],
+        c => readings.get(c).iter().map(|it| it.as_str()).collect(),
+    };
+
+    let mut candidates: Vec<Vec<char>> = Vec::new();
+
+    for reading in base {
+        let chars: Vec<char> = reading.chars().collect();
+        candidates.push(chars.clone());
+
+        if let Some(voiced) = chars.first().and_then(|c| UNVOICED.chars().position(|it| it == *c)) {
+            let mut variant = chars.clone();
+            variant[0] = VOICED.chars().nth(voiced).unwrap_or(variant[0]);
+            candidates.push(variant);
+            if "はひふへほ".contains(chars[0]) {
+                let mut variant = chars.clone();
+                variant[0] = char::from_u32(chars[0] as u32 + 2).unwrap_or(variant[0]);
+                candidates.push(variant);
+            }
+        }
+
+        if chars.len() > 1 && "つくちき".contains(chars[chars.len() - 1]) {
+            let mut variant = chars.clone();
+            variant[chars.len() - 1] = 'っ';
+            candidates.push(variant);
+        }
+    }
+
+    // Prefer longer readings when several segmentations match
+    candidates.sort_by_key(|it| Reverse(it.len()));
+    candidates
+}
+
+/// Checks that a kana run of the word is spelled the same in the reading, starting at `start`
+fn match_kana(run: &[char], kana_vec: &[char], start: usize) -> Result<()> {
+    for (k, c) in run.iter().enumerate() {
+        let kana = kana_vec.get(start + k).with_context(|| MISMATCH)?;
+        if !kana_matches(*c, *kana) {
+            bail!(MISMATCH);
+        }
+    }
+    Ok(())
+}
+
+/// Compares kana regardless of script and size. A long vowel mark matches any vowel.
+fn kana_matches(a: char, b: char) -> bool {
+    let (a, b) = (fold_kana(a), fold_kana(b));
+    a == b || (a == 'ー' && VOWELS.contains(&b)) || (b == 'ー' && VOWELS.contains(&a))
+}
+
+fn fold_kana(c: char) -> char {
+    match c.to_hiragana() {
+        'ぁ' => 'あ', 'ぃ' => 'い', 'ぅ' => 'う', 'ぇ' => 'え', 'ぉ' => 'お',
+        'っ' => 'つ', 'ゃ' => 'や', 'ゅ' => 'ゆ', 'ょ' => 'よ', 'ゎ' => 'わ',
+        'ゕ' => 'か', 'ゖ' => 'け', 'ぢ' => 'じ', 'づ' => 'ず',
+        c => c,
+    }
 }
 
-fn add_pair(pairs: &mut Vec<Vec<String>>, kanji_buf: &mut Vec<char>, kana_buf: &mut Vec<char>) {
-    pairs.push(vec![kanji_buf.iter().collect(), kana_buf.iter().collect()]);
+fn add_pair(pairs: &mut Vec<FuriganaSegment>, kanji_buf: &mut Vec<char>, kana_buf: &mut Vec<char>) {
+    let reading: String = kana_buf.iter().collect();
+    pairs.push(FuriganaSegment {
+        base: kanji_buf.iter().collect(),
+        reading: Some(reading).filter(|it| !it.is_empty()),
+    });
     kanji_buf.clear();
     kana_buf.clear();
 }
\ No newline at end of file
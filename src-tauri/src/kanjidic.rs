@@ -0,0 +1,113 @@
+use anyhow::{Context, Result};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use tokio::task;
+
+use crate::deck::DeckError;
+use crate::ext::char::CharExt;
+
+/// On and kun readings per kanji, in hiragana and without okurigana
+#[derive(Default, Debug)]
+pub struct KanjiReadings {
+    readings: HashMap<char, Vec<String>>,
+}
+
+enum Element {
+    Literal,
+    Reading,
+}
+
+impl KanjiReadings {
+    /// Reads a KANJIDIC2 file without blocking the runtime, the full dictionary takes a moment
+    pub async fn load(path: &str) -> Result<KanjiReadings> {
+        let path = path.to_string();
+        task::spawn_blocking(move || KanjiReadings::from_file(&path)).await
+            .with_context(|| "Error while reading KANJIDIC2")?
+    }
+
+    pub fn from_file(path: &str) -> Result<KanjiReadings> {
+        let file = File::open(path).with_context(|| DeckError::NotFoundError(path.to_string()))?;
+        KanjiReadings::from_xml(BufReader::new(file))
+            .with_context(|| format!("{} is not a KANJIDIC2 file", path))
+    }
+
+    /// Reads the `ja_on` and `ja_kun` readings of a KANJIDIC2 XML document
+    pub fn from_xml<R: BufRead>(reader: R) -> Result<KanjiReadings> {
+        let mut reader = Reader::from_reader(reader);
+        let mut buf = Vec::new();
+        let mut table = KanjiReadings::default();
+        let mut literal: Option<char> = None;
+        let mut element: Option<Element> = None;
+
+        loop {
+            match reader.read_event_into(&mut buf)? {
+                Event::Start(e) => {
+                    element = match e.name().as_ref() {
+                        b"literal" => Some(Element::Literal),
+                        b"reading" => {
+                            let r_type = e.try_get_attribute("r_type")?;
+                            match r_type.as_ref().map(|it| it.value.as_ref()) {
+                                Some(b"ja_on") | Some(b"ja_kun") => Some(Element::Reading),
+                                _ => None,
+                            }
+                        },
+                        _ => None,
+                    };
+                },
+                Event::Text(e) => {
+                    let text = e.decode()?;
+                    match (&element, literal) {
+                        (Some(Element::Literal), _) => literal = text.chars().next(),
+                        (Some(Element::Reading), Some(kanji)) => table.add(kanji, &text),
+                        _ => {},
+                    }
+                },
+                Event::End(e) => {
+                    if e.name().as_ref() == b"character" {
+                        literal = None;
+                    }
+                    element = None;
+                },
+                Event::Eof => break,
+                _ => {},
+            }
+            buf.clear();
+        }
+
+        Ok(table)
+    }
+
+    /// Adds a reading in KANJIDIC2 notation: `つよ.い` is stored as `つよ`, `-がわ` as `がわ`
+    pub fn add(&mut self, kanji: char, reading: &str) {
+        let stem: String = reading.split('.').next().unwrap_or_default()
+            .trim_matches('-')
+            .chars()
+            .map(|c| c.to_hiragana())
+            .collect();
+
+        if stem.is_empty() {
+            return;
+        }
+
+        let readings = self.readings.entry(kanji).or_default();
+
+        if !readings.contains(&stem) {
+            readings.push(stem);
+        }
+    }
+
+    pub fn get(&self, kanji: char) -> &[String] {
+        self.readings.get(&kanji).map(|it| it.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn len(&self) -> usize {
+        self.readings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.readings.is_empty()
+    }
+}
\ No newline at end of file
@@ -1,5 +1,16 @@
 pub mod ext;
+pub mod apkg;
 pub mod deck;
+pub mod dictionary;
+pub mod error;
 pub mod audio;
+pub mod backup;
 pub mod file;
-pub mod kanji;
\ No newline at end of file
+pub mod kanji;
+pub mod kanjidic;
+pub mod media;
+pub mod pitch;
+pub mod progress;
+pub mod romaji;
+pub mod spreadsheet;
+pub mod template;
\ No newline at end of file
@@ -3,23 +3,74 @@
     windows_subsystem = "windows"
 )]
 
-use std::{path::Path, ffi::OsString};
+use std::{path::Path, ffi::OsString, collections::HashMap, sync::{Arc, Mutex}};
 
 use anyhow::Context;
 use app::{
-    deck::{Deck, Package, Template, fetch_all_audio, MEDIA_EXT, AUDIO_SUFFIX, is_valid_extension},
-    file::{create_parent_dir, find_extension},
+    apkg,
+    backup::{self, Backup, DEFAULT_BACKUP_COUNT},
+    deck::{self, Deck, DeckError, Note, Package, Template, FetchOptions, is_valid_extension},
+    dictionary::{Dictionary, DictionaryEntry},
+    error::{CommandError, ErrorKind},
+    ext::string::StringExt,
+    file::{create_parent_dir, deck_path, media_path, static_path, front_path, back_path, css_path, card_back_path, card_names},
+    kanjidic::KanjiReadings,
+    pitch::{self, PitchAccents},
+    progress::{CancelToken, Monitor, Progress},
+    spreadsheet::{self, ImportReport, TableOptions},
+    template::{CardPreview, TemplateProblem},
 };
-use tokio::fs;
+use serde::Serialize;
+use tauri::Manager;
+use tokio::{fs, task};
 
 macro_rules! catch {
     ($a:expr) => {
-        $a.map_err(|e| format!("{:#}", e))?
+        $a.map_err(|e| CommandError::from(anyhow::Error::from(e)))?
     };
 }
 
+/// Cancellation tokens of the running operations, by task id
+#[derive(Default)]
+struct Tasks(Mutex<HashMap<String, CancelToken>>);
+
+/// KANJIDIC2 readings of the last file loaded, by path, parsing the file takes a moment
+#[derive(Default)]
+struct KanjiCache(Mutex<Option<(String, Arc<KanjiReadings>)>>);
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ProgressEvent {
+    task_id: Option<String>,
+    task: &'static str,
+    progress: Progress,
+}
+
+/// Deck JSON after a change to its notes, with the number of notes changed
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NotesUpdate {
+    json: String,
+    count: usize,
+}
+
+/// Deck JSON after fetching audio, keeping the clips fetched before a cancellation
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AudioFetch {
+    json: String,
+    cancelled: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NotesImport {
+    json: String,
+    report: ImportReport,
+}
+
 #[tauri::command]
-async fn read_settings(app_handle: tauri::AppHandle) -> Result<String, String> {
+async fn read_settings(app_handle: tauri::AppHandle) -> Result<String, CommandError> {
     let path = settings_path(app_handle)?;
     catch!(create_parent_dir(Path::new(path.as_os_str())).await);
     let json = fs::read_to_string(path).await;
@@ -27,72 +78,249 @@ async fn read_settings(app_handle: tauri::AppHandle) -> Result<String, String> {
 }
 
 #[tauri::command]
-async fn write_settings(json: String, app_handle: tauri::AppHandle) -> Result<(), String> {
+async fn write_settings(json: String, app_handle: tauri::AppHandle) -> Result<(), CommandError> {
     let path = settings_path(app_handle)?;
     catch!(fs::write(path, json).await);
     Ok(())
 }
 
 #[tauri::command]
-async fn open_deck(dir: String) -> Result<String, String> {
+async fn open_deck(dir: String) -> Result<String, CommandError> {
     if !Path::new(&dir).is_dir() {
-        return Err(format!("{} is not a valid directory", dir))
+        return Err(DeckError::DirectoryError(dir).into())
     }
     let deck = catch!(Deck::from_file(&deck_path(&dir)).await);
     Ok(catch!(deck.to_json()))
 }
 
 #[tauri::command]
-async fn restore_backup(dir: String) -> Result<String, String> {
-    let backup = backup_path(&dir);
-    if !Path::new(&backup).exists() {
-        return Err("Backup file not found".to_string());
-    }
-    let deck = catch!(Deck::from_file(&backup_path(&dir)).await);
+async fn restore_backup(dir: String, name: Option<String>) -> Result<String, CommandError> {
+    let deck = catch!(backup::read_backup(&dir, name.as_deref()).await);
     Ok(catch!(deck.to_json()))
 }
 
 #[tauri::command]
-async fn write_deck(dir: String, json: String) -> Result<(), String> {
+async fn list_backups(dir: String) -> Result<Vec<Backup>, CommandError> {
+    Ok(catch!(backup::list_backups(&dir).await))
+}
+
+#[tauri::command]
+async fn write_deck(dir: String, json: String) -> Result<(), CommandError> {
     let deck = catch!(Deck::from_json(&json));
     catch!(deck.write(&deck_path(&dir)).await);
     Ok(())
 }
 
 #[tauri::command]
-async fn write_backup(dir: String, json: String) -> Result<(), String> {
-    catch!(fs::write(&backup_path(&dir), json).await);
-    Ok(())
+async fn write_backup(dir: String, json: String, keep: Option<usize>) -> Result<Backup, CommandError> {
+    let keep = keep.unwrap_or(DEFAULT_BACKUP_COUNT);
+    Ok(catch!(backup::write_backup(&dir, &json, keep).await))
 }
 
 #[tauri::command]
-async fn fetch_audio(dir: String, json: String) -> Result<String, String> {
+async fn fetch_audio(
+    dir: String,
+    json: String,
+    task_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    tasks: tauri::State<'_, Tasks>,
+) -> Result<AudioFetch, CommandError> {
     let mut deck = catch!(Deck::from_json(&json));
-    catch!(fetch_all_audio(&mut deck, &media_path(&dir)).await);
+    let monitor = start_task(&app_handle, &tasks, &task_id, "fetchAudio");
+    let res = deck::fetch_all_audio_with(&mut deck, &media_path(&dir), &FetchOptions::default(), &monitor).await;
+    finish_task(&tasks, &task_id);
+    catch!(res);
+    Ok(AudioFetch { json: catch!(deck.to_json()), cancelled: monitor.is_cancelled() })
+}
+
+#[tauri::command]
+async fn refetch_audio(dir: String, json: String, note_id: u32) -> Result<String, CommandError> {
+    let mut deck = catch!(Deck::from_json(&json));
+    catch!(deck::refetch_audio(&mut deck, &media_path(&dir), note_id).await);
     Ok(catch!(deck.to_json()))
 }
 
 #[tauri::command]
-async fn write_apkg(dest: String, dir: String, json: String) -> Result<(), String> {
+async fn write_apkg(
+    dest: String,
+    dir: String,
+    json: String,
+    kanjidic: Option<String>,
+    task_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    tasks: tauri::State<'_, Tasks>,
+    kanji_cache: tauri::State<'_, KanjiCache>,
+) -> Result<Vec<String>, CommandError> {
     let template = catch!(Template::from_dir(&dir).await);
     let deck = catch!(Deck::from_json(&json));
-    catch!(deck.write(&deck_path(&dir)).await);
     let mut pkg = catch!(Package::new(deck, template).await);
-    catch!(pkg.write(&dest, &media_path(&dir), Some(&static_path(&dir))).await);
+    catch!(pkg.deck.write(&deck_path(&dir)).await);
+    if let Some(readings) = kanji_readings(&kanji_cache, kanjidic).await? {
+        pkg = pkg.with_readings(readings);
+    }
+    let monitor = start_task(&app_handle, &tasks, &task_id, "writeApkg");
+    let res = pkg.write_with(&dest, &media_path(&dir), Some(&static_path(&dir)), &monitor).await;
+    finish_task(&tasks, &task_id);
+    Ok(catch!(res))
+}
+
+/// Writes the decks of several deck directories, as saved on disk, into one package
+#[tauri::command]
+async fn write_multi_apkg(
+    dest: String,
+    dirs: Vec<String>,
+    kanjidic: Option<String>,
+    task_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    tasks: tauri::State<'_, Tasks>,
+    kanji_cache: tauri::State<'_, KanjiCache>,
+) -> Result<Vec<String>, CommandError> {
+    let readings = kanji_readings(&kanji_cache, kanjidic).await?;
+    let mut packages = Vec::new();
+
+    for dir in dirs.iter() {
+        let deck = catch!(Deck::from_file(&deck_path(dir)).await);
+        let template = catch!(Template::from_dir(dir).await);
+        let cards = deck.cards.clone();
+        let mut pkg = catch!(Package::new(deck, template).await);
+        if pkg.deck.cards != cards {
+            catch!(pkg.deck.write(&deck_path(dir)).await);
+        }
+        if let Some(readings) = &readings {
+            pkg = pkg.with_readings(readings.clone());
+        }
+        packages.push((pkg, media_path(dir), static_path(dir)));
+    }
+
+    let sources: Vec<(&Package, &str, Option<&str>)> = packages.iter()
+        .map(|(pkg, media, static_dir)| (pkg, media.as_str(), Some(static_dir.as_str())))
+        .collect();
+
+    let monitor = start_task(&app_handle, &tasks, &task_id, "writeApkg");
+    let res = deck::write_packages(&sources, &dest, &monitor).await;
+    finish_task(&tasks, &task_id);
+    Ok(catch!(res))
+}
+
+#[tauri::command]
+async fn import_apkg(src: String, dir: String) -> Result<String, CommandError> {
+    let deck = catch!(apkg::import_apkg(&src, &dir).await);
+    Ok(catch!(deck.to_json()))
+}
+
+#[tauri::command]
+async fn export_notes(json: String, dest: String, options: Option<TableOptions>) -> Result<(), CommandError> {
+    let deck = catch!(Deck::from_json(&json));
+    let options = options.unwrap_or_else(|| TableOptions::for_deck(&deck));
+    catch!(spreadsheet::export_notes(&deck, &dest, &options).await);
     Ok(())
 }
 
+#[tauri::command]
+async fn import_notes(json: String, src: String, options: Option<TableOptions>) -> Result<NotesImport, CommandError> {
+    let mut deck = catch!(Deck::from_json(&json));
+    let options = options.unwrap_or_else(|| TableOptions::for_deck(&deck));
+    let report = catch!(spreadsheet::import_notes(&mut deck, &src, &options).await);
+    Ok(NotesImport { json: catch!(deck.to_json()), report })
+}
+
+#[tauri::command]
+async fn fill_pitch(json: String, src: String) -> Result<NotesUpdate, CommandError> {
+    let mut deck = catch!(Deck::from_json(&json));
+    let accents = catch!(PitchAccents::load(&src).await);
+    let count = pitch::fill_pitch(&mut deck, &accents);
+    Ok(NotesUpdate { json: catch!(deck.to_json()), count })
+}
+
+#[tauri::command]
+async fn add_tags(json: String, note_ids: Vec<u32>, tags: Vec<String>) -> Result<NotesUpdate, CommandError> {
+    let mut deck = catch!(Deck::from_json(&json));
+    let count = deck.add_tags(&note_ids, &tags);
+    Ok(NotesUpdate { json: catch!(deck.to_json()), count })
+}
+
+#[tauri::command]
+async fn remove_tags(json: String, note_ids: Vec<u32>, tags: Vec<String>) -> Result<NotesUpdate, CommandError> {
+    let mut deck = catch!(Deck::from_json(&json));
+    let count = deck.remove_tags(&note_ids, &tags);
+    Ok(NotesUpdate { json: catch!(deck.to_json()), count })
+}
+
+#[tauri::command]
+async fn search_notes(json: String, query: String) -> Result<Vec<Note>, CommandError> {
+    let deck = catch!(Deck::from_json(&json));
+    Ok(deck.search(&query).into_iter().cloned().collect())
+}
+
+#[tauri::command]
+async fn import_dictionary(
+    src: String,
+    task_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    tasks: tauri::State<'_, Tasks>,
+) -> Result<usize, CommandError> {
+    let path = dictionary_path(&app_handle)?.to_string_lossy().to_string();
+    catch!(create_parent_dir(Path::new(&path)).await);
+    let monitor = start_task(&app_handle, &tasks, &task_id, "importDictionary");
+    let res = task::spawn_blocking(move || {
+        Dictionary::open(&path)?.import_file(&src, &monitor)
+    }).await;
+    finish_task(&tasks, &task_id);
+    Ok(catch!(catch!(res)))
+}
+
+#[tauri::command]
+async fn lookup_word(word: String, app_handle: tauri::AppHandle) -> Result<Vec<DictionaryEntry>, CommandError> {
+    let path = dictionary_path(&app_handle)?.to_string_lossy().to_string();
+    if !Path::new(&path).exists() {
+        return Err(CommandError::new(ErrorKind::NotFound, "No dictionary imported").with_path(&path));
+    }
+    let dict = catch!(Dictionary::open(&path));
+    Ok(catch!(dict.lookup(&word.remove_ruby())))
+}
+
 #[tauri::command]
 async fn check_template(dir: String) -> Result<bool, ()> {
+    let cards = card_names(&dir);
     Ok(
-        Path::new(&front_path(&dir)).exists() &&
-        Path::new(&back_path(&dir)).exists() &&
+        !cards.is_empty() &&
+        cards.iter().all(|card| Path::new(&card_back_path(&dir, card)).exists()) &&
         Path::new(&css_path(&dir)).exists()
     )
 }
 
+/// Renders a card of the note. The media directory of the deck is added to the asset
+/// protocol scope, as the preview loads its media from there.
+#[tauri::command]
+async fn preview_card(
+    dir: String,
+    json: String,
+    note: Note,
+    card: Option<String>,
+    kanjidic: Option<String>,
+    app_handle: tauri::AppHandle,
+    kanji_cache: tauri::State<'_, KanjiCache>,
+) -> Result<CardPreview, CommandError> {
+    let deck = catch!(Deck::from_json(&json));
+    let template = catch!(Template::from_dir(&dir).await);
+    let mut pkg = catch!(Package::new(deck, template).await);
+    if let Some(readings) = kanji_readings(&kanji_cache, kanjidic).await? {
+        pkg = pkg.with_readings(readings);
+    }
+    catch!(app_handle.asset_protocol_scope().allow_directory(media_path(&dir), true));
+    Ok(catch!(pkg.preview(&note, card.as_deref(), &media_path(&dir)).await))
+}
+
+/// Problems of the card templates, with line numbers, checked against the fields of the deck
+#[tauri::command]
+async fn validate_template(dir: String, json: String) -> Result<Vec<TemplateProblem>, CommandError> {
+    let deck = catch!(Deck::from_json(&json));
+    let template = catch!(Template::from_dir(&dir).await);
+    Ok(template.check(&deck.field_names()))
+}
+
 #[tauri::command]
-async fn write_template(dir: String, app_handle: tauri::AppHandle) -> Result<(), String> {
+async fn write_template(dir: String, app_handle: tauri::AppHandle) -> Result<(), CommandError> {
     let resolver = app_handle.path_resolver();
     let front_res = resolver.resolve_resource("res/front.html").unwrap();
     let back_res = resolver.resolve_resource("res/back.html").unwrap();
@@ -103,14 +331,11 @@ async fn write_template(dir: String, app_handle: tauri::AppHandle) -> Result<(),
     let css_dest = css_path(&dir);
     let font_dest = format!("{}/_jpaf.ttf", static_path(&dir));
 
-    if Path::new(&front_dest).exists() {
-        catch!(fs::copy(&front_dest, format!("{}.bk", front_dest)).await);
-    }
-    if Path::new(&back_dest).exists() {
-        catch!(fs::copy(&front_dest, format!("{}.bk", back_path(&dir))).await);
-    }
-    if Path::new(&css_dest).exists() {
-        catch!(fs::copy(&front_dest, format!("{}.bk", css_path(&dir))).await);
+    // Only the default card type is rewritten, other card types are left as they are
+    for dest in [&front_dest, &back_dest, &css_dest] {
+        if Path::new(dest).exists() {
+            catch!(fs::copy(dest, format!("{}.bk", dest)).await);
+        }
     }
 
     catch!(create_parent_dir(Path::new(&font_dest)).await);
@@ -129,21 +354,23 @@ async fn write_template(dir: String, app_handle: tauri::AppHandle) -> Result<(),
 }
 
 #[tauri::command]
-async fn move_media(dest_dir: String, src_file: String, deck_id: usize, note_id: u16) -> Result<(), String> {
+async fn move_media(dest_dir: String, src_file: String, json: String, note_id: u32) -> Result<(), CommandError> {
+    let deck = catch!(Deck::from_json(&json));
+
     let ext = catch!(Path::new(&src_file)
         .extension()
         .and_then(|it| it.to_str())
         .with_context(|| format!("Failed to parse file extension: {}", src_file)));
 
     if !is_valid_extension(ext) {
-        return Err(format!("{} is not supported ({})", ext, src_file));
+        return Err(DeckError::MediaError(src_file).into());
     }
 
-    let dest = format!("{}/{}{}.{}", media_path(&dest_dir), deck_id, note_id, ext);
+    let dest = format!("{}/{}.{}", media_path(&dest_dir), deck.media_key(note_id), ext);
     let dest_path = Path::new(&dest);
 
     if dest_path.exists() {
-        return Err(format!("File already exists: {}", dest));
+        return Err(DeckError::FileExistsError(dest).into());
     } else {
         catch!(create_parent_dir(&dest_path).await);
     }
@@ -154,83 +381,124 @@ async fn move_media(dest_dir: String, src_file: String, deck_id: usize, note_id:
 }
 
 #[tauri::command]
-async fn upgrade_media_naming(dir: String, json: String) -> Result<(), String> {
-    let deck = catch!(Deck::from_json(&json));
+async fn upgrade_media_naming(
+    dir: String,
+    json: String,
+    task_id: Option<String>,
+    app_handle: tauri::AppHandle,
+    tasks: tauri::State<'_, Tasks>,
+) -> Result<String, CommandError> {
+    let mut deck = catch!(Deck::from_json(&json));
+    let monitor = start_task(&app_handle, &tasks, &task_id, "upgradeMediaNaming");
+    let res = deck::upgrade_media_naming_with(&mut deck, &media_path(&dir), &monitor).await;
+    finish_task(&tasks, &task_id);
+    catch!(res);
+    catch!(deck.write(&deck_path(&dir)).await);
+    Ok(catch!(deck.to_json()))
+}
 
-    let media = media_path(&dir);
+#[tauri::command]
+async fn cancel_task(task_id: String, tasks: tauri::State<'_, Tasks>) -> Result<(), CommandError> {
+    if let Some(token) = tasks.0.lock().unwrap().get(&task_id) {
+        token.cancel();
+    }
+    Ok(())
+}
 
-    catch!(fs::create_dir_all(&media).await);
+/// Registers a cancellable operation whose progress is emitted as `progress` events
+fn start_task(app_handle: &tauri::AppHandle, tasks: &Tasks, task_id: &Option<String>, task: &'static str) -> Monitor {
+    let token = CancelToken::new();
 
-    for note in deck.notes.iter() {
-        let opt_ext = find_extension(&format!("{}/{}", media, note.word), &(*MEDIA_EXT));
-        let id = catch!(note.id.with_context(|| "Note ID must be defined"));
-        if let Some(ext) = opt_ext {
-            let src = format!("{}/{}.{}", media, note.word, ext);
-            move_media(dir.to_string(), src, deck.id, id).await?;
-        }
-        let audio_src = format!("{}/{}_r.mp3", media, note.word);
-        if Path::new(&audio_src).exists() {
-            catch!(fs::rename(audio_src, format!("{}/{}{}{}", media, deck.id, id, AUDIO_SUFFIX)).await);
-        }
+    if let Some(id) = task_id {
+        tasks.0.lock().unwrap().insert(id.clone(), token.clone());
     }
 
-    Ok(())
-}
+    let app_handle = app_handle.clone();
+    let task_id = task_id.clone();
 
-fn deck_path(dir: &str) -> String {
-    format!("{}/deck.json", dir)
+    Monitor::new(token, move |progress| {
+        let event = ProgressEvent { task_id: task_id.clone(), task, progress: progress.clone() };
+        if let Err(err) = app_handle.emit_all("progress", event) {
+            eprintln!("{}", err);
+        }
+    })
 }
 
-fn backup_path(dir: &str) -> String {
-    format!("{}/deck.json.bk", dir)
+fn finish_task(tasks: &Tasks, task_id: &Option<String>) {
+    if let Some(id) = task_id {
+        tasks.0.lock().unwrap().remove(id);
+    }
 }
 
-fn media_path(dir: &str) -> String {
-    format!("{}/media", dir)
-}
+/// Readings of the KANJIDIC2 file at `path`, loaded on first use
+async fn kanji_readings(cache: &KanjiCache, path: Option<String>) -> Result<Option<Arc<KanjiReadings>>, CommandError> {
+    let path = match path.filter(|it| !it.is_empty()) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
 
-fn static_path(dir: &str) -> String {
-    format!("{}/static", dir)
-}
+    if let Some((cached_path, readings)) = cache.0.lock().unwrap().as_ref() {
+        if *cached_path == path {
+            return Ok(Some(readings.clone()));
+        }
+    }
 
-fn front_path(dir: &str) -> String {
-    format!("{}/front.html", dir)
+    let readings = Arc::new(catch!(KanjiReadings::load(&path).await));
+    *cache.0.lock().unwrap() = Some((path, readings.clone()));
+    Ok(Some(readings))
 }
 
-fn back_path(dir: &str) -> String {
-    format!("{}/back.html", dir)
+fn settings_path(app_handle: tauri::AppHandle) -> Result<OsString, CommandError> {
+    config_file(&app_handle, "settings.json")
 }
 
-fn css_path(dir: &str) -> String {
-    format!("{}/style.css", dir)
+fn dictionary_path(app_handle: &tauri::AppHandle) -> Result<OsString, CommandError> {
+    config_file(app_handle, "jmdict.sqlite")
 }
 
-fn settings_path(app_handle: tauri::AppHandle) -> Result<OsString, String> {
+fn config_file(app_handle: &tauri::AppHandle, name: &str) -> Result<OsString, CommandError> {
     let app_name = app_handle.package_info().name.to_string();
-    let settings_file =  format!("{}/settings.json", app_name);
-    let path = tauri::api::path::config_dir().map(|dir| dir.join(settings_file).into_os_string());
+    let file =  format!("{}/{}", app_name, name);
+    let path = tauri::api::path::config_dir().map(|dir| dir.join(file).into_os_string());
     if let Some(path) = path {
         Ok(path)
     } else {
-        Err("Cannot retrieve settings file".to_string())
+        Err(CommandError::new(ErrorKind::NotFound, format!("Cannot retrieve {}", name)))
     }
 }
 
 fn main() {
     tauri::Builder::default()
+        .manage(Tasks::default())
+        .manage(KanjiCache::default())
         .invoke_handler(tauri::generate_handler![
             read_settings,
             write_settings,
             open_deck,
             restore_backup,
+            list_backups,
             write_deck,
             write_backup,
             write_apkg,
+            write_multi_apkg,
+            import_apkg,
+            export_notes,
+            import_notes,
+            import_dictionary,
+            fill_pitch,
+            search_notes,
+            add_tags,
+            remove_tags,
+            lookup_word,
             check_template,
             write_template,
+            validate_template,
+            preview_card,
             move_media,
             fetch_audio,
+            refetch_audio,
             upgrade_media_naming,
+            cancel_task,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
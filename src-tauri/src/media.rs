@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tokio::task;
+
+use crate::deck::MEDIA_EXT;
+
+/// Files of a media directory by name without extension, read once so that notes
+/// can be matched to their files without probing the filesystem per extension
+#[derive(Default, Debug)]
+pub struct MediaIndex {
+    dir: String,
+    files: HashMap<String, Vec<String>>,
+}
+
+impl MediaIndex {
+    /// Same as [`MediaIndex::scan`], reading the directory on a blocking thread
+    pub async fn load(dir: &str) -> Result<MediaIndex> {
+        let dir = dir.to_string();
+        task::spawn_blocking(move || MediaIndex::scan(&dir)).await
+            .with_context(|| "Error while reading the media directory")?
+    }
+
+    /// Lists the files of `dir`, a missing directory giving an empty index
+    pub fn scan(dir: &str) -> Result<MediaIndex> {
+        let mut index = MediaIndex { dir: dir.to_string(), ..Default::default() };
+
+        if !Path::new(dir).exists() {
+            return Ok(index);
+        }
+
+        let entries = std::fs::read_dir(dir).with_context(|| format!("Cannot read {}", dir))?;
+
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Ok(name) = entry.file_name().into_string() {
+                index.insert(name);
+            }
+        }
+
+        for names in index.files.values_mut() {
+            names.sort();
+        }
+
+        Ok(index)
+    }
+
+    fn insert(&mut self, name: String) {
+        self.files.entry(file_stem(&name).to_string()).or_default().push(name);
+    }
+
+    pub fn path(&self, name: &str) -> String {
+        format!("{}/{}", self.dir, name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.files.get(file_stem(name)).map_or(false, |names| names.iter().any(|it| it == name))
+    }
+
+    /// Media files named `<key>.<ext>`, extensions matching `MEDIA_EXT` whatever their case,
+    /// in the order of `MEDIA_EXT` so that videos come first
+    pub fn media(&self, key: &str) -> Vec<&str> {
+        let mut names: Vec<(usize, &str)> = self.files.get(key)
+            .map(|names| {
+                names.iter()
+                    .filter_map(|name| Some((media_rank(name)?, name.as_str())))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        names.sort();
+        names.into_iter().map(|(_, name)| name).collect()
+    }
+
+    /// Files whose name without extension is not one of `stems`, like the media of deleted notes
+    pub fn unused(&self, stems: &HashSet<String>) -> Vec<&str> {
+        let mut names: Vec<&str> = self.files.iter()
+            .filter(|(stem, _)| !stems.contains(*stem))
+            .flat_map(|(_, names)| names.iter().map(|it| it.as_str()))
+            .collect();
+
+        names.sort();
+        names
+    }
+}
+
+/// File name without its last extension
+pub fn file_stem(name: &str) -> &str {
+    name.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(name)
+}
+
+fn media_rank(name: &str) -> Option<usize> {
+    let (_, ext) = name.rsplit_once('.')?;
+    let ext = ext.to_lowercase();
+    MEDIA_EXT.iter().position(|it| *it == ext)
+}
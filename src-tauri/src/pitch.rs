@@ -0,0 +1,200 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use tokio::task;
+
+use crate::deck::{Deck, DeckError};
+use crate::ext::string::StringExt;
+
+static SMALL_KANA: &str = "ゃゅょぁぃぅぇぉゎャュョァィゥェォヮ";
+
+/// Horizontal distance between two morae in SVG graphs
+const MORA_WIDTH: usize = 35;
+
+/// How pitch accent is written into the Pitch field of a package
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum PitchStyle {
+    /// Line graph with a dot per mora
+    Svg,
+    /// Kana with `pitch-high`/`pitch-low` classes, to be styled with overlines
+    Html,
+}
+
+impl Default for PitchStyle {
+    fn default() -> Self {
+        PitchStyle::Svg
+    }
+}
+
+/// Accents by word and reading, the number of the mora after which pitch drops (0 for none)
+#[derive(Default, Debug)]
+pub struct PitchAccents {
+    accents: HashMap<(String, String), Vec<u8>>,
+}
+
+impl PitchAccents {
+    /// Reads an accents file without blocking the runtime, Kanjium's has over 100,000 lines
+    pub async fn load(path: &str) -> Result<PitchAccents> {
+        let path = path.to_string();
+        task::spawn_blocking(move || PitchAccents::from_file(&path)).await
+            .with_context(|| "Error while reading pitch accents")?
+    }
+
+    pub fn from_file(path: &str) -> Result<PitchAccents> {
+        let file = File::open(path).with_context(|| DeckError::NotFoundError(path.to_string()))?;
+        PitchAccents::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to read {}", path))
+    }
+
+    /// Reads Kanjium's `accents.txt`: `word<TAB>reading<TAB>accents` per line, the reading
+    /// being empty for kana words and accents separated by commas, like `1,0`
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<PitchAccents> {
+        let mut table = PitchAccents::default();
+
+        for line in reader.lines() {
+            let line = line?;
+            let mut columns = line.split('\t');
+
+            let (word, reading, accents) = match (columns.next(), columns.next(), columns.next()) {
+                (Some(word), Some(reading), Some(accents)) => (word, reading, accents),
+                _ => continue,
+            };
+
+            // Some accents are annotated with the part of speech, as in `(名)0`
+            let accents: Vec<u8> = accents.split(',')
+                .filter_map(|it| it.trim_start_matches(|c: char| !c.is_ascii_digit()).parse().ok())
+                .collect();
+
+            if !accents.is_empty() {
+                let reading = if reading.is_empty() { word } else { reading };
+                table.accents.insert((word.to_string(), reading.to_string().to_hiragana()), accents);
+            }
+        }
+
+        Ok(table)
+    }
+
+    pub fn get(&self, word: &str, reading: &str) -> Option<&[u8]> {
+        self.accents.get(&(word.to_string(), reading.to_string().to_hiragana())).map(|it| it.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.accents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accents.is_empty()
+    }
+}
+
+/// Sets the pitch of the notes that have none, returns the number of notes updated
+pub fn fill_pitch(deck: &mut Deck, accents: &PitchAccents) -> usize {
+    let mut count = 0;
+
+    for note in deck.notes.iter_mut().filter(|it| it.pitch.is_none()) {
+        if let Some(pitch) = accents.get(&note.word.remove_ruby(), &note.kana()) {
+            note.pitch = Some(pitch.to_vec());
+            count += 1;
+        }
+    }
+
+    count
+}
+
+/// Splits kana into morae, small ゃゅょ and the like belong to the preceding kana
+pub fn split_morae(kana: &str) -> Vec<String> {
+    let mut morae: Vec<String> = Vec::new();
+
+    for c in kana.chars().filter(|c| !c.is_whitespace()) {
+        match morae.last_mut() {
+            Some(last) if SMALL_KANA.contains(c) => last.push(c),
+            _ => morae.push(c.to_string()),
+        }
+    }
+
+    morae
+}
+
+/// Pitch of each mora followed by a particle, `true` being high
+pub fn pitch_pattern(mora_count: usize, drop: u8) -> Vec<bool> {
+    let drop = drop as usize;
+
+    (0..=mora_count)
+        .map(|i| match drop {
+            0 => i != 0,
+            1 => i == 0,
+            _ => i != 0 && i < drop,
+        })
+        .collect()
+}
+
+impl PitchStyle {
+    /// Renders one graph per accent
+    pub fn render(&self, kana: &str, accents: &[u8]) -> String {
+        accents.iter()
+            .map(|drop| match self {
+                PitchStyle::Svg => render_svg(kana, *drop),
+                PitchStyle::Html => render_html(kana, *drop),
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+fn render_html(kana: &str, drop: u8) -> String {
+    let morae = split_morae(kana);
+    let pattern = pitch_pattern(morae.len(), drop);
+    let mut s = String::from("<span class=\"pitch\">");
+
+    for (i, mora) in morae.iter().enumerate() {
+        let class = if pattern[i] { "pitch-high" } else { "pitch-low" };
+        let drop_class = if pattern[i] && !pattern[i + 1] { " pitch-drop" } else { "" };
+        s.push_str(&format!("<span class=\"{}{}\">{}</span>", class, drop_class, mora));
+    }
+
+    s.push_str("</span>");
+    s
+}
+
+fn render_svg(kana: &str, drop: u8) -> String {
+    let morae = split_morae(kana);
+    let pattern = pitch_pattern(morae.len(), drop);
+    let width = MORA_WIDTH * pattern.len();
+    let point = |i: usize| (MORA_WIDTH / 2 + MORA_WIDTH * i, if pattern[i] { 5 } else { 30 });
+
+    let mut s = format!(
+        "<svg class=\"pitch\" xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"75\" viewBox=\"0 0 {} 75\">",
+        width, width,
+    );
+
+    for (i, mora) in morae.iter().enumerate() {
+        s.push_str(&format!(
+            "<text x=\"{}\" y=\"67.5\" text-anchor=\"middle\" style=\"font-size:20px;fill:currentColor\">{}</text>",
+            point(i).0, mora,
+        ));
+    }
+
+    for i in 1..pattern.len() {
+        let ((x1, y1), (x2, y2)) = (point(i - 1), point(i));
+        s.push_str(&format!(
+            "<path d=\"M{} {} L{} {}\" style=\"stroke:currentColor;stroke-width:1.5\"/>",
+            x1, y1, x2, y2,
+        ));
+    }
+
+    for i in 0..pattern.len() {
+        let (x, y) = point(i);
+        // The particle following the word is drawn hollow
+        let fill = if i < morae.len() { "currentColor" } else { "none" };
+        s.push_str(&format!(
+            "<circle r=\"5\" cx=\"{}\" cy=\"{}\" style=\"stroke:currentColor;stroke-width:1.5;fill:{}\"/>",
+            x, y, fill,
+        ));
+    }
+
+    s.push_str("</svg>");
+    s
+}
\ No newline at end of file
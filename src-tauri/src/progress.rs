@@ -0,0 +1,71 @@
+use serde::Serialize;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TaskError {
+    #[error("Operation cancelled")]
+    CancelledError,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+    pub current: Option<String>,
+    pub failures: usize,
+}
+
+/// Cancellation flag shared between a long-running operation and whoever started it
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+/// Progress sink and cancellation token handed to long-running operations
+pub struct Monitor {
+    token: CancelToken,
+    on_progress: Box<dyn Fn(&Progress) + Send + Sync>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl Monitor {
+    pub fn new<F>(token: CancelToken, on_progress: F) -> Monitor
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        Monitor { token, on_progress: Box::new(on_progress) }
+    }
+
+    /// A monitor that is never cancelled and discards progress
+    pub fn silent() -> Monitor {
+        Monitor::new(CancelToken::new(), |_| {})
+    }
+
+    pub fn token(&self) -> CancelToken {
+        self.token.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub fn report(&self, progress: &Progress) {
+        (self.on_progress)(progress);
+    }
+}
@@ -0,0 +1,210 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::ext::string::StringExt;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Romanization {
+    /// し → shi, ちゃ → cha
+    Hepburn,
+    /// し → si, ちゃ → tya
+    Kunrei,
+}
+
+/// Hiragana with their Hepburn and Kunrei spellings
+static KANA: [(&str, &str, &str); 83] = [
+    ("あ", "a", "a"), ("い", "i", "i"), ("う", "u", "u"), ("え", "e", "e"), ("お", "o", "o"),
+    ("か", "ka", "ka"), ("き", "ki", "ki"), ("く", "ku", "ku"), ("け", "ke", "ke"), ("こ", "ko", "ko"),
+    ("さ", "sa", "sa"), ("し", "shi", "si"), ("す", "su", "su"), ("せ", "se", "se"), ("そ", "so", "so"),
+    ("た", "ta", "ta"), ("ち", "chi", "ti"), ("つ", "tsu", "tu"), ("て", "te", "te"), ("と", "to", "to"),
+    ("な", "na", "na"), ("に", "ni", "ni"), ("ぬ", "nu", "nu"), ("ね", "ne", "ne"), ("の", "no", "no"),
+    ("は", "ha", "ha"), ("ひ", "hi", "hi"), ("ふ", "fu", "hu"), ("へ", "he", "he"), ("ほ", "ho", "ho"),
+    ("ま", "ma", "ma"), ("み", "mi", "mi"), ("む", "mu", "mu"), ("め", "me", "me"), ("も", "mo", "mo"),
+    ("や", "ya", "ya"), ("ゆ", "yu", "yu"), ("よ", "yo", "yo"),
+    ("ら", "ra", "ra"), ("り", "ri", "ri"), ("る", "ru", "ru"), ("れ", "re", "re"), ("ろ", "ro", "ro"),
+    ("わ", "wa", "wa"), ("ゐ", "i", "i"), ("ゑ", "e", "e"), ("を", "o", "o"), ("ん", "n", "n"),
+    ("が", "ga", "ga"), ("ぎ", "gi", "gi"), ("ぐ", "gu", "gu"), ("げ", "ge", "ge"), ("ご", "go", "go"),
+    ("ざ", "za", "za"), ("じ", "ji", "zi"), ("ず", "zu", "zu"), ("ぜ", "ze", "ze"), ("ぞ", "zo", "zo"),
+    ("だ", "da", "da"), ("ぢ", "ji", "zi"), ("づ", "zu", "zu"), ("で", "de", "de"), ("ど", "do", "do"),
+    ("ば", "ba", "ba"), ("び", "bi", "bi"), ("ぶ", "bu", "bu"), ("べ", "be", "be"), ("ぼ", "bo", "bo"),
+    ("ぱ", "pa", "pa"), ("ぴ", "pi", "pi"), ("ぷ", "pu", "pu"), ("ぺ", "pe", "pe"), ("ぽ", "po", "po"),
+    ("ゔ", "vu", "vu"),
+    ("ぁ", "a", "a"), ("ぃ", "i", "i"), ("ぅ", "u", "u"), ("ぇ", "e", "e"), ("ぉ", "o", "o"),
+    ("ゃ", "ya", "ya"), ("ゅ", "yu", "yu"), ("ょ", "yo", "yo"), ("ゎ", "wa", "wa"),
+];
+
+/// Consonants of the i-column kana combined with small ゃゅょ, as in きゃ → kya
+static YOON: [(char, &str, &str); 12] = [
+    ('き', "ky", "ky"), ('ぎ', "gy", "gy"), ('し', "sh", "sy"), ('じ', "j", "zy"),
+    ('ち', "ch", "ty"), ('ぢ', "j", "zy"), ('に', "ny", "ny"), ('ひ', "hy", "hy"),
+    ('び', "by", "by"), ('ぴ', "py", "py"), ('み', "my", "my"), ('り', "ry", "ry"),
+];
+
+/// Kana followed by a small vowel spelled as one syllable, as in ふぁ → fa
+static DIGRAPHS: [(&str, &str, &str); 7] = [
+    ("ふぁ", "fa", "fa"), ("ふぃ", "fi", "fi"), ("ふぇ", "fe", "fe"), ("ふぉ", "fo", "fo"),
+    ("しぇ", "she", "sye"), ("ちぇ", "che", "tye"), ("じぇ", "je", "zye"),
+];
+
+/// Spellings accepted by `romaji_to_kana` on top of the Hepburn and Kunrei ones
+static EXTRA_ROMAJI: [(&str, &str); 5] = [
+    ("wo", "を"), ("n'", "ん"), ("xtu", "っ"), ("ltu", "っ"), ("-", "ー"),
+];
+
+static ROMAJI: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    let mut map: HashMap<String, String> = HashMap::new();
+
+    // Small kana are only reachable with an x prefix, as in xa → ぁ
+    for (kana, hepburn, kunrei) in KANA.iter() {
+        let small = "ぁぃぅぇぉゃゅょゎ".contains(kana);
+        for romaji in [hepburn, kunrei] {
+            let key = if small { format!("x{}", romaji) } else { romaji.to_string() };
+            map.entry(key).or_insert_with(|| kana.to_string());
+        }
+    }
+
+    for (kana, hepburn, kunrei) in YOON.iter() {
+        for (small, vowel) in [('ゃ', 'a'), ('ゅ', 'u'), ('ょ', 'o')] {
+            for prefix in [hepburn, kunrei] {
+                map.entry(format!("{}{}", prefix, vowel)).or_insert_with(|| format!("{}{}", kana, small));
+            }
+        }
+    }
+
+    for (kana, hepburn, kunrei) in DIGRAPHS.iter() {
+        for romaji in [hepburn, kunrei] {
+            map.insert(romaji.to_string(), kana.to_string());
+        }
+    }
+
+    for (romaji, kana) in EXTRA_ROMAJI.iter() {
+        map.insert(romaji.to_string(), kana.to_string());
+    }
+
+    map
+});
+
+/// Romanizes kana, other characters are kept as is. ー doubles the previous vowel.
+pub fn kana_to_romaji(kana: &str, system: Romanization) -> String {
+    let chars: Vec<char> = kana.to_string().to_hiragana().chars().collect();
+    let spell = |romaji: &(&str, &str)| match system {
+        Romanization::Hepburn => romaji.0.to_string(),
+        Romanization::Kunrei => romaji.1.to_string(),
+    };
+
+    let mut syllables: Vec<String> = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let pair: String = chars[i..chars.len().min(i + 2)].iter().collect();
+
+        if let Some((_, hepburn, kunrei)) = DIGRAPHS.iter().find(|it| it.0 == pair) {
+            syllables.push(spell(&(hepburn, kunrei)));
+            i += 2;
+            continue;
+        }
+
+        let yoon = YOON.iter().find(|it| it.0 == c);
+        let next = chars.get(i + 1).and_then(|n| [('ゃ', 'a'), ('ゅ', 'u'), ('ょ', 'o')].iter().find(|it| it.0 == *n));
+
+        if let (Some((_, hepburn, kunrei)), Some((_, vowel))) = (yoon, next) {
+            syllables.push(format!("{}{}", spell(&(hepburn, kunrei)), vowel));
+            i += 2;
+            continue;
+        }
+
+        let syllable = match KANA.iter().find(|it| it.0.starts_with(c)) {
+            Some((_, hepburn, kunrei)) => spell(&(hepburn, kunrei)),
+            None => c.to_string(),
+        };
+
+        syllables.push(syllable);
+        i += 1;
+    }
+
+    let mut s = String::new();
+
+    for (i, syllable) in syllables.iter().enumerate() {
+        let next = syllables.get(i + 1);
+
+        match syllable.as_str() {
+            // Sokuon doubles the next consonant, tch in Hepburn
+            "っ" => {
+                if let Some(first) = next.and_then(|it| it.chars().next()).filter(|it| it.is_ascii_alphabetic()) {
+                    if first == 'c' && system == Romanization::Hepburn {
+                        s.push('t');
+                    } else {
+                        s.push(first);
+                    }
+                }
+            },
+            "ー" => {
+                if let Some(vowel) = s.chars().last().filter(|it| "aiueo".contains(*it)) {
+                    s.push(vowel);
+                }
+            },
+            "n" if next.and_then(|it| it.chars().next()).map_or(false, |it| "aiueoy".contains(it)) => {
+                s.push_str("n'");
+            },
+            _ => s.push_str(syllable),
+        }
+    }
+
+    s
+}
+
+/// Converts romaji in either system to hiragana, leaving what cannot be read as is
+pub fn romaji_to_kana(romaji: &str) -> String {
+    let chars: Vec<char> = romaji.to_lowercase().chars().collect();
+    let mut s = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        // Doubled consonant: kk → っk
+        if c.is_ascii_alphabetic() && !"aiueon".contains(c) && next == Some(c) {
+            s.push('っ');
+            i += 1;
+            continue;
+        }
+
+        if c == 't' && next == Some('c') {
+            s.push('っ');
+            i += 1;
+            continue;
+        }
+
+        // nn is ん, the second n still starts a syllable when a vowel follows: konnichiwa
+        if c == 'n' && next == Some('n') {
+            let vowel_follows = chars.get(i + 2).map_or(false, |it| "aiueoy".contains(*it));
+            s.push('ん');
+            i += if vowel_follows { 1 } else { 2 };
+            continue;
+        }
+
+        let matched = (1..=4).rev()
+            .filter(|len| i + len <= chars.len())
+            .find_map(|len| {
+                let key: String = chars[i..i + len].iter().collect();
+                ROMAJI.get(&key).map(|kana| (kana, len))
+            });
+
+        match matched {
+            Some((kana, len)) => {
+                s.push_str(kana);
+                i += len;
+            },
+            None => {
+                s.push(c);
+                i += 1;
+            },
+        }
+    }
+
+    s
+}
\ No newline at end of file
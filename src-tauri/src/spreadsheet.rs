@@ -0,0 +1,322 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+use thiserror::Error;
+use tokio::fs;
+
+use crate::deck::{Deck, Note};
+use crate::file;
+
+#[derive(Error, Debug)]
+pub enum TableError {
+    #[error("No column is mapped to the word field")]
+    WordColumnError,
+    #[error("The delimiter must be an ASCII character, not {0}")]
+    DelimiterError(char),
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum NoteField {
+    Id,
+    Word,
+    Reading,
+    Definition,
+    Transcription,
+    UseReading,
+    /// Space separated
+    Tags,
+    SubDeck,
+    /// Space separated accents, like `0 2`
+    Pitch,
+    /// A custom field of the deck, by name
+    Custom(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum MergeStrategy {
+    /// Rows update the note with the same ID, rows without a known ID are added
+    ById,
+    /// Rows update the note with the same word, other rows are added
+    ByWord,
+    /// Every row is added as a new note
+    Append,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Column {
+    pub header: String,
+    pub field: NoteField,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TableOptions {
+    /// Column delimiter, guessed from the file extension when not set
+    pub delimiter: Option<char>,
+    pub columns: Vec<Column>,
+    pub merge: MergeStrategy,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RowError {
+    /// 1-based line number, the header being line 1
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportReport {
+    pub added: usize,
+    pub updated: usize,
+    pub failed: Vec<RowError>,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        let column = |header: &str, field| Column { header: header.to_string(), field };
+
+        TableOptions {
+            delimiter: None,
+            columns: vec![
+                column("id", NoteField::Id),
+                column("word", NoteField::Word),
+                column("reading", NoteField::Reading),
+                column("definition", NoteField::Definition),
+                column("transcription", NoteField::Transcription),
+                column("useReading", NoteField::UseReading),
+                column("tags", NoteField::Tags),
+                column("subDeck", NoteField::SubDeck),
+                column("pitch", NoteField::Pitch),
+            ],
+            merge: MergeStrategy::ById,
+        }
+    }
+}
+
+impl TableOptions {
+    /// Default columns followed by one column per custom field of the deck, named after it
+    pub fn for_deck(deck: &Deck) -> TableOptions {
+        let mut options = TableOptions::default();
+        for name in deck.fields.iter() {
+            options.columns.push(Column { header: name.clone(), field: NoteField::Custom(name.clone()) });
+        }
+        options
+    }
+
+    fn delimiter_for(&self, path: &str) -> Result<u8> {
+        if let Some(delimiter) = self.delimiter {
+            if !delimiter.is_ascii() {
+                bail!(TableError::DelimiterError(delimiter));
+            }
+            return Ok(delimiter as u8);
+        }
+        match Path::new(path).extension().and_then(|it| it.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("tsv") || ext.eq_ignore_ascii_case("txt") => Ok(b'\t'),
+            _ => Ok(b','),
+        }
+    }
+}
+
+pub async fn export_notes(deck: &Deck, dest: &str, options: &TableOptions) -> Result<()> {
+    let mut buf = Vec::new();
+    write_notes(deck, &mut buf, options.delimiter_for(dest)?, options)?;
+    file::write_atomic(dest, buf).await
+        .with_context(|| format!("Failed to write {}", dest))
+}
+
+pub async fn import_notes(deck: &mut Deck, src: &str, options: &TableOptions) -> Result<ImportReport> {
+    let data = fs::read(src).await
+        .with_context(|| format!("Cannot read {}", src))?;
+    read_notes(deck, data.as_slice(), options.delimiter_for(src)?, options)
+}
+
+pub fn write_notes<W: Write>(deck: &Deck, writer: W, delimiter: u8, options: &TableOptions) -> Result<()> {
+    let mut writer = csv::WriterBuilder::new().delimiter(delimiter).from_writer(writer);
+
+    writer.write_record(options.columns.iter().map(|it| &it.header))?;
+
+    for note in deck.notes.iter() {
+        writer.write_record(options.columns.iter().map(|it| field_value(note, &it.field)))?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Merges the rows of a table into the deck. Invalid rows are skipped and listed in the report.
+pub fn read_notes<R: Read>(deck: &mut Deck, reader: R, delimiter: u8, options: &TableOptions) -> Result<ImportReport> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(reader);
+
+    let headers = reader.headers()?.clone();
+    let mut indices: Vec<(usize, NoteField)> = Vec::new();
+
+    for column in options.columns.iter() {
+        if let Some(index) = headers.iter().position(|it| it.trim() == column.header) {
+            indices.push((index, column.field.clone()));
+        }
+    }
+
+    if !indices.iter().any(|(_, field)| *field == NoteField::Word) && options.merge != MergeStrategy::ById {
+        bail!(TableError::WordColumnError);
+    }
+
+    let mut report = ImportReport::default();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for (index, note) in deck.notes.iter().enumerate() {
+        if let Some(key) = merge_key(note, options.merge) {
+            positions.insert(key, index);
+        }
+    }
+
+    for (row, record) in reader.records().enumerate() {
+        // Quoted values may span lines, so rows are located by the reader's position
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                let line = err.position().map_or(row + 2, |it| it.line() as usize);
+                report.failed.push(RowError { line, message: err.to_string() });
+                continue;
+            }
+        };
+        let line = record.position().map_or(row + 2, |it| it.line() as usize);
+
+        let values: Vec<(&NoteField, &str)> = indices.iter()
+            .filter_map(|(index, field)| record.get(*index).map(|it| (field, it.trim())))
+            .collect();
+
+        let mut note = Note::default();
+
+        if let Err(err) = apply_values(&mut note, &values) {
+            report.failed.push(RowError { line, message: format!("{:#}", err) });
+            continue;
+        }
+
+        let existing = merge_key(&note, options.merge).and_then(|key| positions.get(&key).copied());
+
+        let res = match existing {
+            Some(index) => {
+                // The ID of an existing note never changes
+                let values: Vec<(&NoteField, &str)> = values.iter()
+                    .filter(|(field, _)| **field != NoteField::Id)
+                    .copied()
+                    .collect();
+                let mut updated = deck.notes[index].clone();
+                apply_values(&mut updated, &values).and_then(|_| validate(&updated)).map(|_| {
+                    deck.notes[index] = updated;
+                    report.updated += 1;
+                })
+            },
+            None => validate(&note).map(|_| {
+                if options.merge != MergeStrategy::ById {
+                    note.id = None;
+                }
+                if let Some(key) = merge_key(&note, options.merge) {
+                    positions.insert(key, deck.notes.len());
+                }
+                deck.notes.push(note);
+                report.added += 1;
+            }),
+        };
+
+        if let Err(err) = res {
+            report.failed.push(RowError { line, message: format!("{:#}", err) });
+        }
+    }
+
+    deck.assign_ids()?;
+
+    Ok(report)
+}
+
+fn merge_key(note: &Note, merge: MergeStrategy) -> Option<String> {
+    match merge {
+        MergeStrategy::ById => note.id.map(|it| it.to_string()),
+        MergeStrategy::ByWord if !note.word.is_empty() => Some(note.word.clone()),
+        _ => None,
+    }
+}
+
+fn field_value(note: &Note, field: &NoteField) -> String {
+    match field {
+        NoteField::Id => note.id.map(|it| it.to_string()).unwrap_or_default(),
+        NoteField::Word => note.word.clone(),
+        NoteField::Reading => note.reading.clone().unwrap_or_default(),
+        NoteField::Definition => note.definition.clone(),
+        NoteField::Transcription => note.transcription.clone(),
+        NoteField::UseReading => note.use_reading.to_string(),
+        NoteField::Tags => note.tags.join(" "),
+        NoteField::SubDeck => note.sub_deck.clone().unwrap_or_default(),
+        NoteField::Pitch => note.pitch.iter().flatten().map(|it| it.to_string()).collect::<Vec<String>>().join(" "),
+        NoteField::Custom(name) => note.fields.get(name).cloned().unwrap_or_default(),
+    }
+}
+
+fn apply_values(note: &mut Note, values: &[(&NoteField, &str)]) -> Result<()> {
+    for (field, value) in values.iter() {
+        match field {
+            NoteField::Id => {
+                note.id = if value.is_empty() {
+                    None
+                } else {
+                    Some(value.parse().with_context(|| format!("Invalid ID: {}", value))?)
+                };
+            },
+            NoteField::Word => note.word = value.to_string(),
+            NoteField::Reading => note.reading = Some(value.to_string()).filter(|it| !it.is_empty()),
+            NoteField::Definition => note.definition = value.to_string(),
+            NoteField::Transcription => note.transcription = value.to_string(),
+            NoteField::UseReading => note.use_reading = parse_bool(value)?,
+            NoteField::Tags => note.tags = value.split_whitespace().map(|it| it.to_string()).collect(),
+            NoteField::SubDeck => note.sub_deck = Some(value.to_string()).filter(|it| !it.is_empty()),
+            NoteField::Pitch => {
+                let accents = value.split_whitespace()
+                    .map(|it| it.parse().with_context(|| format!("Invalid pitch accent: {}", it)))
+                    .collect::<Result<Vec<u8>>>()?;
+                note.pitch = Some(accents).filter(|it| !it.is_empty());
+            },
+            NoteField::Custom(name) => {
+                if value.is_empty() {
+                    note.fields.remove(name);
+                } else {
+                    note.fields.insert(name.clone(), value.to_string());
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+fn validate(note: &Note) -> Result<()> {
+    if note.word.is_empty() {
+        bail!("Word is empty");
+    }
+    if note.definition.is_empty() {
+        bail!("Definition is empty");
+    }
+    if note.reading.as_deref() == Some(note.word.as_str()) {
+        bail!("Word and Reading are identical");
+    }
+    if note.use_reading && note.reading.is_none() {
+        bail!("Use Reading is set but Reading is empty");
+    }
+    Ok(())
+}
+
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "" | "false" | "0" | "no" => Ok(false),
+        "true" | "1" | "yes" => Ok(true),
+        _ => bail!("Invalid boolean: {}", value),
+    }
+}
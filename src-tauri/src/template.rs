@@ -0,0 +1,331 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::deck::{IMAGE_EXT, VIDEO_EXT};
+
+/// Fields Anki provides to templates on top of the note fields
+pub static SPECIAL_FIELDS: [&str; 8] = ["FrontSide", "Tags", "Type", "Deck", "Subdeck", "Card", "CardFlag", "CardID"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TagKind {
+    /// `{{Field}}` or with filters, `{{furigana:Field}}`
+    Field,
+    /// `{{#Field}}`
+    Section,
+    /// `{{^Field}}`
+    InvertedSection,
+    /// `{{/Field}}`
+    End,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Tag {
+    pub kind: TagKind,
+    pub field: String,
+    /// Filters in the order they apply, the one next to the field name first
+    pub filters: Vec<String>,
+    /// 1-based line number
+    pub line: usize,
+}
+
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TemplateProblem {
+    pub card: String,
+    /// `front` or `back`
+    pub side: String,
+    pub line: usize,
+    pub message: String,
+}
+
+/// A card rendered as Anki shows it, media being referenced by file name
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CardPreview {
+    pub front: String,
+    pub back: String,
+    pub css: String,
+}
+
+impl fmt::Display for TemplateProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}, line {}: {}", self.card, self.side, self.line, self.message)
+    }
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Tag(Tag),
+}
+
+/// Splits a card template into text and tags, skipping comments. Unreadable tags are
+/// kept as text and returned as problems, `(line, message)`.
+fn tokenize(text: &str) -> (Vec<Token<'_>>, Vec<(usize, String)>) {
+    let mut tokens = Vec::new();
+    let mut problems = Vec::new();
+    let mut pos = 0;
+
+    while let Some(start) = text[pos..].find("{{").map(|it| pos + it) {
+        let line = text[..start].matches('\n').count() + 1;
+        let content = match text[start + 2..].find("}}") {
+            Some(len) => &text[start + 2..start + 2 + len],
+            None => {
+                problems.push((line, "Unclosed tag".to_string()));
+                break;
+            }
+        };
+
+        if content.contains("{{") {
+            problems.push((line, "Unclosed tag".to_string()));
+            tokens.push(Token::Text(&text[pos..start + 2]));
+            pos = start + 2;
+            continue;
+        }
+
+        tokens.push(Token::Text(&text[pos..start]));
+        pos = start + 2 + content.len() + 2;
+        let content = content.trim();
+
+        if content.starts_with('!') {
+            continue;
+        }
+
+        let (kind, name) = match content.chars().next() {
+            Some('#') => (TagKind::Section, &content[1..]),
+            Some('^') => (TagKind::InvertedSection, &content[1..]),
+            Some('/') => (TagKind::End, &content[1..]),
+            _ => (TagKind::Field, content),
+        };
+
+        // Filters come before the field name, as in furigana:Reading
+        let (field, filters) = match kind {
+            TagKind::Field => {
+                let mut parts = name.rsplit(':');
+                let field = parts.next().unwrap_or_default().trim();
+                (field, parts.map(|it| it.trim().to_string()).collect())
+            },
+            _ => (name.trim(), Vec::new()),
+        };
+
+        if field.is_empty() {
+            problems.push((line, format!("Empty tag {}", content)));
+            continue;
+        }
+
+        tokens.push(Token::Tag(Tag { kind, field: field.to_string(), filters, line }));
+    }
+
+    tokens.push(Token::Text(&text[pos..]));
+
+    (tokens, problems)
+}
+
+/// Reads the mustache tags of a card template, skipping comments. Tags that cannot
+/// be read are returned as problems, `(line, message)`.
+pub fn parse_tags(text: &str) -> (Vec<Tag>, Vec<(usize, String)>) {
+    let (tokens, problems) = tokenize(text);
+    let tags = tokens.into_iter()
+        .filter_map(|token| match token {
+            Token::Tag(tag) => Some(tag),
+            Token::Text(_) => None,
+        })
+        .collect();
+
+    (tags, problems)
+}
+
+/// Lists the unreadable tags, unknown fields and unbalanced sections of a card template
+/// as `(line, message)`, in line order
+pub fn check_tags(text: &str, fields: &[String]) -> Vec<(usize, String)> {
+    let (tags, mut problems) = parse_tags(text);
+    let mut sections: Vec<&Tag> = Vec::new();
+
+    for tag in tags.iter() {
+        match tag.kind {
+            TagKind::Section | TagKind::InvertedSection => sections.push(tag),
+            // Sections opened after the one being closed were left open
+            TagKind::End => match sections.iter().rposition(|it| it.field == tag.field) {
+                Some(index) => {
+                    for open in sections.drain(index..).skip(1) {
+                        problems.push((open.line, format!("Section {} is not closed", open.field)));
+                    }
+                },
+                None => problems.push((tag.line, format!("/{} closes no section", tag.field))),
+            },
+            TagKind::Field => {},
+        }
+
+        if tag.kind != TagKind::End && !is_known_field(&tag.field, fields) {
+            problems.push((tag.line, format!("Unknown field {}", tag.field)));
+        }
+    }
+
+    for open in sections {
+        problems.push((open.line, format!("Section {} is not closed", open.field)));
+    }
+
+    problems.sort_by_key(|it| it.0);
+    problems
+}
+
+pub fn is_known_field(field: &str, fields: &[String]) -> bool {
+    fields.iter().any(|it| it == field) || SPECIAL_FIELDS.contains(&field)
+}
+
+/// Renders a card template the way Anki does with the given field values. Sections show
+/// when their field is not blank, `[sound:]` tags become players for the files they name.
+pub fn render(text: &str, fields: &HashMap<String, String>) -> String {
+    let (tokens, _) = tokenize(text);
+    let mut html = String::new();
+
+    render_tokens(&tokens, fields, &mut html);
+
+    render_sound_tags(&html)
+}
+
+fn render_tokens(tokens: &[Token], fields: &HashMap<String, String>, html: &mut String) {
+    let mut i = 0;
+
+    while i < tokens.len() {
+        match &tokens[i] {
+            Token::Text(text) => html.push_str(text),
+            Token::Tag(tag) => match tag.kind {
+                TagKind::Field => {
+                    let value = fields.get(&tag.field).cloned().unwrap_or_default();
+                    html.push_str(&tag.filters.iter().fold(value, |value, filter| apply_filter(filter, &value)));
+                },
+                TagKind::Section | TagKind::InvertedSection => {
+                    let end = section_end(tokens, i);
+                    let blank = fields.get(&tag.field).map_or(true, |it| it.trim().is_empty());
+
+                    if blank == (tag.kind == TagKind::InvertedSection) {
+                        render_tokens(&tokens[i + 1..end], fields, html);
+                    }
+                    i = end;
+                },
+                TagKind::End => {},
+            },
+        }
+        i += 1;
+    }
+}
+
+/// Index of the tag closing the section opened at `start`, or the end of the tokens
+fn section_end(tokens: &[Token], start: usize) -> usize {
+    let field = match &tokens[start] {
+        Token::Tag(tag) => &tag.field,
+        Token::Text(_) => return start,
+    };
+    let mut depth = 0;
+
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        if let Token::Tag(tag) = token {
+            if &tag.field != field {
+                continue;
+            }
+            match tag.kind {
+                TagKind::Section | TagKind::InvertedSection => depth += 1,
+                TagKind::End => depth -= 1,
+                TagKind::Field => {},
+            }
+            if depth == 0 {
+                return i;
+            }
+        }
+    }
+
+    tokens.len()
+}
+
+fn apply_filter(filter: &str, value: &str) -> String {
+    match filter {
+        "furigana" | "kana" | "kanji" => ruby_filter(filter, value),
+        "text" => strip_html(value),
+        _ => value.to_string(),
+    }
+}
+
+/// Anki's ruby filters over `base[reading]` text, the base running back to the previous
+/// space or tag. `furigana` gives `<ruby>` elements, `kana` the readings and `kanji` the bases.
+pub fn ruby_filter(filter: &str, text: &str) -> String {
+    let mut html = String::new();
+    let mut rest = text;
+
+    while let Some(open) = rest.find('[') {
+        let close = match rest[open..].find(']') {
+            Some(len) => open + len,
+            None => break,
+        };
+
+        let before = &rest[..open];
+        let reading = &rest[open + 1..close];
+        let base_start = before.rfind(|c| c == ' ' || c == '>').map(|it| it + 1).unwrap_or(0);
+        let base = &before[base_start..];
+
+        if base.is_empty() || reading.is_empty() || reading.starts_with("sound:") {
+            html.push_str(&rest[..close + 1]);
+        } else {
+            // The space separating a ruby base from the preceding text is not displayed
+            html.push_str(before[..base_start].strip_suffix(' ').unwrap_or(&before[..base_start]));
+            match filter {
+                "kana" => html.push_str(reading),
+                "kanji" => html.push_str(base),
+                _ => html.push_str(&format!("<ruby><rb>{}</rb><rt>{}</rt></ruby>", base, reading)),
+            }
+        }
+
+        rest = &rest[close + 1..];
+    }
+
+    html.push_str(rest);
+    html
+}
+
+fn strip_html(text: &str) -> String {
+    let mut s = String::new();
+    let mut in_tag = false;
+
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => s.push(c),
+            _ => {},
+        }
+    }
+
+    s
+}
+
+/// Replaces `[sound:file]` with an `<audio>`, `<video>` or `<img>` element, by extension
+fn render_sound_tags(html: &str) -> String {
+    let mut s = String::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find("[sound:") {
+        let end = match rest[start..].find(']') {
+            Some(len) => start + len,
+            None => break,
+        };
+
+        let file = &rest[start + 7..end];
+        let ext = Path::new(file).extension().and_then(|it| it.to_str()).unwrap_or_default().to_lowercase();
+
+        s.push_str(&rest[..start]);
+
+        if VIDEO_EXT.contains(&ext.as_str()) {
+            s.push_str(&format!("<video controls src=\"{}\"></video>", file));
+        } else if IMAGE_EXT.contains(&ext.as_str()) {
+            s.push_str(&format!("<img src=\"{}\">", file));
+        } else {
+            s.push_str(&format!("<audio controls src=\"{}\"></audio>", file));
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    s.push_str(rest);
+    s
+}
@@ -0,0 +1,100 @@
+use app::apkg::import_apkg;
+use app::deck::{MediaNaming, NOTE_FIELDS};
+use rusqlite::{params, Connection};
+use std::{fs::{self, File}, io::Write, path::Path};
+use zip::{write::FileOptions, ZipWriter};
+
+#[tokio::test]
+async fn import_test() {
+    let dir = "tests/test-files/import-test";
+    let deck = import_apkg("tests/test-files/import.apkg", dir).await.unwrap();
+
+    assert_eq!(deck.id, 99);
+    assert_eq!(deck.name, "Imported test");
+    assert_eq!(deck.media_naming, MediaNaming::Separated);
+    assert_eq!(deck.model_id, Some(1234));
+    assert_eq!(deck.notes.len(), 2);
+
+    let note = &deck.notes[0];
+    assert_eq!(note.word, "侍");
+    assert_eq!(note.reading.as_deref(), Some("さむらい"));
+    assert_eq!(note.guid.as_deref(), Some("abc"));
+    assert_eq!(note.tags, ["anime::shogun", "vocab"]);
+    assert_eq!(note.audio_state.as_deref(), Some("OK"));
+    assert!(Path::new(&format!("{}/media/99_1r.mp3", dir)).exists());
+    assert!(Path::new(&format!("{}/media/99_1.mp4", dir)).exists());
+    assert!(Path::new(&format!("{}/front.html", dir)).exists());
+    assert!(deck.notes[1].reading.is_none());
+
+    assert!(import_apkg("tests/test-files/import.apkg", dir).await.is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn import_custom_fields() {
+    let dir = "tests/test-files/import-fields-test";
+    let apkg = "tests/test-files/import-fields.apkg";
+
+    // A package of this tool with a custom field after the built-in ones
+    let mut names: Vec<&str> = NOTE_FIELDS.to_vec();
+    names.push("Notes");
+    let fields: Vec<String> = names.iter().enumerate()
+        .map(|(ord, name)| format!(r#"{{ "name": "{}", "ord": {} }}"#, name, ord))
+        .collect();
+    let models = format!(
+        r#"{{ "555": {{ "flds": [{}], "tmpls": [{{ "name": "Card 1", "qfmt": "{{{{Word}}}}", "afmt": "{{{{Notes}}}}" }}] }} }}"#,
+        fields.join(", "),
+    );
+    let values = ["1", "侍", "さむらい", "samurai", "", "", "", "", "", "Edo period"].join("\x1f");
+
+    let collection = "tests/test-files/import-fields.anki2";
+    let _ = fs::remove_file(collection);
+    let conn = Connection::open(collection).unwrap();
+    conn.execute_batch(
+        "CREATE TABLE col (models TEXT, decks TEXT);
+         CREATE TABLE notes (id INTEGER, guid TEXT, mid INTEGER, flds TEXT, tags TEXT);
+         CREATE TABLE cards (nid INTEGER, did INTEGER);
+         INSERT INTO cards VALUES (1, 7);",
+    ).unwrap();
+    conn.execute("INSERT INTO col VALUES (?1, ?2)", params![models, r#"{ "7": { "name": "Custom" } }"#]).unwrap();
+    conn.execute("INSERT INTO notes VALUES (1, 'abc', 555, ?1, '')", params![values]).unwrap();
+    drop(conn);
+
+    let mut zip = ZipWriter::new(File::create(apkg).unwrap());
+    zip.start_file("collection.anki2", FileOptions::default()).unwrap();
+    zip.write_all(&fs::read(collection).unwrap()).unwrap();
+    zip.finish().unwrap();
+    fs::remove_file(collection).unwrap();
+
+    let deck = import_apkg(apkg, dir).await.unwrap();
+
+    assert_eq!(deck.model_id, Some(555));
+    assert_eq!(deck.fields, ["Notes"]);
+    assert_eq!(deck.notes[0].word, "侍");
+    assert_eq!(deck.notes[0].fields.get("Notes").map(|it| it.as_str()), Some("Edo period"));
+
+    fs::remove_dir_all(dir).unwrap();
+    fs::remove_file(apkg).unwrap();
+}
+
+#[tokio::test]
+async fn import_newer_format() {
+    let dir = "tests/test-files/import-newer-test";
+    let apkg = "tests/test-files/import-newer.apkg";
+
+    // Layout of Anki 2.1.50+ packages, the old collection only holds a placeholder note
+    let mut zip = ZipWriter::new(File::create(apkg).unwrap());
+    for name in ["collection.anki2", "collection.anki21b", "media"] {
+        zip.start_file(name, FileOptions::default()).unwrap();
+        zip.write_all(b"").unwrap();
+    }
+    zip.finish().unwrap();
+
+    let err = import_apkg(apkg, dir).await.err().unwrap();
+    assert!(format!("{:#}", err).contains("legacy support"));
+    assert!(!Path::new(&format!("{}/deck.json", dir)).exists());
+
+    let _ = fs::remove_dir_all(dir);
+    fs::remove_file(apkg).unwrap();
+}
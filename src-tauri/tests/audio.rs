@@ -1,5 +1,5 @@
-use app::audio::{fetch_audio, AudioError};
-use std::{path::Path, fs};
+use app::audio::{fetch_audio, AudioError, AudioSourceConfig, AudioSources, RateLimiter};
+use std::{path::Path, fs, time::{Duration, Instant}};
 
 #[cfg(test)] #[macro_use]
 extern crate assert_matches;
@@ -17,4 +17,39 @@ async fn fetch_invalid_audio() {
     let res = fetch_audio("tests/test-files/no.mp3", None, "いじる").await;
     let err: AudioError = res.unwrap_err().downcast().unwrap();
     assert_matches!(err, AudioError::UnavailableError(_));
-}
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn fetch_local_fallback() {
+    let sources = AudioSources::from_config(&[
+        AudioSourceConfig::LocalFolder { path: "tests/test-files/media".to_string() },
+        AudioSourceConfig::LocalFolder { path: "tests/test-files/audio".to_string() },
+    ]);
+    let filename = "tests/test-files/audio-test/侍.mp3";
+    let source = sources.fetch(filename, Some("侍"), "さむらい", None).await.unwrap();
+    assert_eq!(source, "localFolder:tests/test-files/audio");
+    assert!(Path::new(&filename).exists());
+    fs::remove_file(filename).unwrap();
+
+    let res = sources.fetch(filename, Some("侍"), "さむらい", Some(&source)).await;
+    assert!(res.is_err());
+
+    let res = sources.fetch(filename, Some("狂う"), "くるう", None).await;
+    let err: AudioError = res.unwrap_err().downcast().unwrap();
+    assert_matches!(err, AudioError::UnavailableError(_));
+}
+
+#[tokio::test]
+async fn rate_limit() {
+    let limiter = RateLimiter::new(Duration::from_millis(50));
+    let start = Instant::now();
+    for _ in 0..3 {
+        limiter.wait("a").await;
+    }
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    // "a" has a slot pending, another host does not wait for it
+    let start = Instant::now();
+    limiter.wait("b").await;
+    assert!(start.elapsed() < Duration::from_millis(50));
+}
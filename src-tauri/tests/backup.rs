@@ -0,0 +1,22 @@
+use app::backup::*;
+use std::fs;
+
+#[tokio::test]
+async fn rotate_backups() {
+    let dir = "tests/test-files/backup-test";
+    let json = |name: &str| format!(r#"{{ "id": 1, "name": "{}", "notes": [] }}"#, name);
+
+    for name in ["a", "b", "c"] {
+        write_backup(dir, &json(name), 2).await.unwrap();
+    }
+
+    let backups = list_backups(dir).await.unwrap();
+    assert_eq!(backups.len(), 2);
+    assert!(backups[0].timestamp > backups[1].timestamp);
+
+    assert_eq!(read_backup(dir, None).await.unwrap().name, "c");
+    assert_eq!(read_backup(dir, Some(&backups[1].name)).await.unwrap().name, "b");
+    assert!(read_backup(dir, Some("deck-0.json")).await.is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
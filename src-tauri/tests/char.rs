@@ -10,4 +10,27 @@ fn test_is_kanji() {
     for c in s.chars() {
         assert!(!c.is_kanji());
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_extended_kanji() {
+    for c in ['㐂', '𠮟', '﨑', '〇'] {
+        assert!(c.is_kanji());
+    }
+    assert!(!'々'.is_kanji());
+    assert!('々'.is_iteration_mark());
+    assert!('々'.is_ruby_base());
+    assert!('ヶ'.is_ruby_base());
+}
+
+#[test]
+fn test_kana_and_punctuation() {
+    assert!('ゑ'.is_hiragana());
+    assert!('ー'.is_katakana());
+    assert!('ｶ'.is_katakana());
+    assert!(!'・'.is_kana());
+    for c in ['・', '。', '「', '〜', '！'] {
+        assert!(c.is_japanese_punctuation());
+    }
+    assert!(!'ａ'.is_japanese_punctuation());
+}
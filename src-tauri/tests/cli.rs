@@ -0,0 +1,48 @@
+use std::{fs, path::Path, process::{Command, Output}};
+
+fn cli(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_jp-anki-cli")).args(args).output().unwrap()
+}
+
+#[test]
+fn arguments() {
+    let output = cli(&[]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("Usage"));
+
+    let output = cli(&["unknown", "dir"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unknown command unknown"));
+
+    assert!(!cli(&["search", "dir"]).status.success());
+    assert!(!cli(&["tag", "dir"]).status.success());
+    assert!(!cli(&["build", "out.apkg"]).status.success());
+    assert!(!cli(&["import", "dir"]).status.success());
+}
+
+#[test]
+fn init_template() {
+    let dir = "tests/test-files/cli-test";
+
+    assert!(cli(&["init-template", dir]).status.success());
+    for file in ["front.html", "back.html", "style.css", "static/_jpaf.ttf"] {
+        assert!(Path::new(&format!("{}/{}", dir, file)).exists(), "{} missing", file);
+    }
+
+    assert!(!cli(&["init-template", dir]).status.success());
+    assert!(cli(&["init-template", dir, "--force"]).status.success());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn import() {
+    let dir = "tests/test-files/cli-import-test";
+
+    let output = cli(&["import", dir, "tests/test-files/import.apkg"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Imported 2 notes"));
+    assert!(Path::new(&format!("{}/deck.json", dir)).exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -1,4 +1,10 @@
 use app::deck::*;
+use app::progress::{CancelToken, Monitor, TaskError};
+use std::path::Path;
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+
+#[cfg(test)] #[macro_use]
+extern crate assert_matches;
 
 #[tokio::test]
 async fn read_deck() {
@@ -14,6 +20,260 @@ async fn read_deck() {
 
     assert_eq!(pkg.deck.notes[0].word, "test");
 
-    pkg.write(&format!("{}/out.apkg", dir), &format!("{}/media", dir), None).await.unwrap();
-    pkg.to_deck().write(&format!("{}/deck.json", dir)).await.unwrap();
-}
\ No newline at end of file
+    // Written outside the test files so that the deck.json fixture stays as it is
+    let out_dir = std::env::temp_dir().join("jp-anki-read-deck");
+    std::fs::create_dir_all(&out_dir).unwrap();
+    let out_dir = out_dir.to_str().unwrap();
+
+    pkg.write(&format!("{}/out.apkg", out_dir), &format!("{}/media", dir), None).await.unwrap();
+    pkg.to_deck().write(&format!("{}/deck.json", out_dir)).await.unwrap();
+    assert!(Deck::from_file(&format!("{}/deck.json", out_dir)).await.is_ok());
+
+    std::fs::remove_dir_all(out_dir).unwrap();
+}
+
+#[test]
+fn media_naming() {
+    let mut deck = Deck::from_json(r#"{ "id": 1, "name": "test", "notes": [
+        { "id": 12, "word": "a", "definition": "", "transcription": "" }
+    ] }"#).unwrap();
+
+    assert_eq!(deck.media_naming, MediaNaming::Legacy);
+    assert_eq!(deck.media_key(12), MediaNaming::Legacy.media_key(11, 2));
+
+    deck.media_naming = MediaNaming::Separated;
+    assert_eq!(deck.media_key(12), "1_12");
+    assert_ne!(deck.media_key(12), MediaNaming::Separated.media_key(11, 2));
+
+    deck.notes[0].guid = Some("1121".to_string());
+    assert_eq!(deck.note_guid(&deck.notes[0]).unwrap(), "1121");
+}
+
+#[test]
+fn note_ids() {
+    let deck = Deck::from_json(r#"{ "id": 1, "name": "test", "notes": [
+        { "id": 70000, "word": "a", "definition": "", "transcription": "" },
+        { "word": "b", "definition": "", "transcription": "" }
+    ] }"#).unwrap();
+    assert_eq!(deck.notes[1].id, Some(70001));
+
+    let err = Deck::from_json(r#"{ "id": 1, "name": "test", "notes": [
+        { "id": 2, "word": "a", "definition": "", "transcription": "" },
+        { "id": 2, "word": "b", "definition": "", "transcription": "" }
+    ] }"#).err().unwrap();
+    let err: DeckError = err.downcast().unwrap();
+    assert_matches!(err, DeckError::DuplicateIdError(2));
+}
+
+#[test]
+fn model_id() {
+    let mut deck = Deck::from_json(r#"{ "id": 1, "name": "test", "notes": [] }"#).unwrap();
+    assert_eq!(deck.model_id, Some(1));
+
+    deck.id = 2;
+    assert_eq!(deck.note_type_id(), 1);
+
+    let deck = Deck::from_json(r#"{ "id": 1, "modelId": 5, "name": "test", "notes": [] }"#).unwrap();
+    assert_eq!(deck.note_type_id(), 5);
+}
+
+#[tokio::test]
+async fn upgrade_media_naming_progress() {
+    let media = "tests/test-files/upgrade-test";
+    std::fs::create_dir_all(media).unwrap();
+    std::fs::write(format!("{}/111.mp4", media), "").unwrap();
+
+    let mut deck = Deck::from_json(r#"{ "id": 1, "name": "test", "notes": [
+        { "id": 11, "word": "a", "definition": "", "transcription": "" }
+    ] }"#).unwrap();
+
+    let token = CancelToken::new();
+    token.cancel();
+    let err = upgrade_media_naming_with(&mut deck, media, &Monitor::new(token, |_| {})).await.unwrap_err();
+    assert_matches!(err.downcast().unwrap(), TaskError::CancelledError);
+    assert_eq!(deck.media_naming, MediaNaming::Legacy);
+
+    let done = Arc::new(AtomicUsize::new(0));
+    let counter = done.clone();
+    let monitor = Monitor::new(CancelToken::new(), move |progress| {
+        counter.store(progress.done, Ordering::SeqCst);
+    });
+    upgrade_media_naming_with(&mut deck, media, &monitor).await.unwrap();
+
+    assert_eq!(done.load(Ordering::SeqCst), 1);
+    assert_eq!(deck.media_naming, MediaNaming::Separated);
+    assert_eq!(deck.notes[0].guid.as_deref(), Some("1111"));
+    assert!(Path::new(&format!("{}/1_11.mp4", media)).exists());
+
+    std::fs::remove_dir_all(media).unwrap();
+}
+
+#[tokio::test]
+async fn upgrade_media_naming_rollback() {
+    let media = "tests/test-files/rollback-test";
+    std::fs::create_dir_all(media).unwrap();
+    std::fs::write(format!("{}/111.mp4", media), "").unwrap();
+    std::fs::write(format!("{}/112.mp4", media), "").unwrap();
+    std::fs::write(format!("{}/1_12.mp4", media), "").unwrap();
+
+    let mut deck = Deck::from_json(r#"{ "id": 1, "name": "test", "notes": [
+        { "id": 11, "word": "a", "definition": "", "transcription": "" },
+        { "id": 12, "word": "b", "definition": "", "transcription": "" }
+    ] }"#).unwrap();
+
+    let err = upgrade_media_naming(&mut deck, media).await.unwrap_err();
+    assert_matches!(err.downcast().unwrap(), DeckError::FileExistsError(_));
+    assert_eq!(deck.media_naming, MediaNaming::Legacy);
+    assert!(Path::new(&format!("{}/111.mp4", media)).exists());
+    assert!(!Path::new(&format!("{}/1_11.mp4", media)).exists());
+
+    std::fs::remove_dir_all(media).unwrap();
+}
+
+#[tokio::test]
+async fn card_templates() {
+    let dir = "tests/test-files/cards-test";
+    std::fs::create_dir_all(dir).unwrap();
+    std::fs::write(format!("{}/front.html", dir), "{{Word}}").unwrap();
+    std::fs::write(format!("{}/back.html", dir), "{{Definition}}").unwrap();
+    std::fs::write(format!("{}/listening.front.html", dir), "{{Pronunciation}}").unwrap();
+    std::fs::write(format!("{}/listening.back.html", dir), "{{Word}}").unwrap();
+
+    let template = Template::from_dir(dir).await.unwrap();
+    let names: Vec<&str> = template.cards.iter().map(|it| it.name.as_str()).collect();
+    assert_eq!(names, ["Default", "listening"]);
+    assert_eq!(template.cards[1].front, "{{Pronunciation}}");
+
+    // A card type added later keeps the existing ones at their position
+    std::fs::write(format!("{}/a.front.html", dir), "{{Word}}").unwrap();
+    std::fs::write(format!("{}/a.back.html", dir), "{{Word}}").unwrap();
+    let deck = Deck::from_json(r#"{ "id": 1, "name": "test", "cards": ["Default", "listening"], "notes": [] }"#).unwrap();
+    let pkg = Package::new(deck, Template::from_dir(dir).await.unwrap()).await.unwrap();
+    let names: Vec<&str> = pkg.template.cards.iter().map(|it| it.name.as_str()).collect();
+    assert_eq!(names, ["Default", "listening", "a"]);
+    assert_eq!(pkg.deck.cards, ["Default", "listening", "a"]);
+
+    std::fs::write(format!("{}/production.front.html", dir), "{{Definition}}").unwrap();
+    let err = Template::from_dir(dir).await.err().unwrap();
+    assert_matches!(err.downcast().unwrap(), DeckError::TemplateError(_));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn custom_fields() {
+    let deck = Deck::from_json(r#"{ "id": 1, "name": "test", "fields": ["Sentence", "Source"], "notes": [
+        { "id": 1, "word": "a", "definition": "", "transcription": "", "fields": { "Sentence": "s", "Other": "o" } }
+    ] }"#).unwrap();
+
+    assert_eq!(deck.field_names()[9..], ["Sentence", "Source"]);
+    assert_eq!(deck.notes[0].fields["Sentence"], "s");
+    assert!(deck.validate("tests/test-files/media").iter().any(|it| it.contains("Other")));
+
+    let err = Deck::from_json(r#"{ "id": 1, "name": "test", "fields": ["word"], "notes": [] }"#).err().unwrap();
+    assert_matches!(err.downcast().unwrap(), DeckError::FieldError(_));
+
+    let template = Template {
+        cards: vec![CardTemplate {
+            name: "Default".to_string(),
+            front: "{{Word}}{{#Sentence}}{{furigana:Sentence}}{{/Sentence}}".to_string(),
+            back: "{{FrontSide}}<hr>{{Notes}}{{! comment }}".to_string(),
+        }],
+        css: String::new(),
+    };
+    let problems = template.check(&deck.field_names());
+    assert_eq!(problems.len(), 1);
+    assert_eq!(problems[0].message, "Unknown field Notes");
+}
+
+#[test]
+fn note_tags() {
+    let mut deck = Deck::from_json(r#"{ "id": 1, "name": "test", "tags": ["jp anki"], "notes": [
+        { "id": 1, "word": "a", "definition": "", "transcription": "", "tags": ["book"] },
+        { "id": 2, "word": "b", "definition": "", "transcription": "" }
+    ] }"#).unwrap();
+
+    assert_eq!(deck.note_tags(&deck.notes[0]), ["jp_anki", "book"]);
+
+    assert_eq!(deck.add_tags(&[1, 2], &["book".to_string(), "chapter 1".to_string()]), 2);
+    assert_eq!(deck.notes[0].tags, ["book", "chapter_1"]);
+    assert_eq!(deck.notes[1].tags, ["book", "chapter_1"]);
+
+    assert_eq!(deck.remove_tags(&[2], &["book".to_string()]), 1);
+    assert_eq!(deck.notes[1].tags, ["chapter_1"]);
+
+    // Tags differing only in case are the same tag in Anki
+    assert_eq!(deck.add_tags(&[1], &["Book".to_string()]), 0);
+    deck.notes[1].tags.push("JP_Anki".to_string());
+    assert_eq!(deck.note_tags(&deck.notes[1]), ["jp_anki", "chapter_1"]);
+
+    // Deck tags stay when only some notes are given, as they apply to the others too
+    assert_eq!(deck.remove_tags(&[2], &["jp_anki".to_string()]), 0);
+    assert_eq!(deck.tags, ["jp anki"]);
+    assert_eq!(deck.notes[1].tags, ["chapter_1"]);
+    assert_eq!(deck.note_tags(&deck.notes[0]), ["jp_anki", "book", "chapter_1"]);
+
+    // and are removed along with the note tags when every note is given
+    assert_eq!(deck.remove_tags(&[1, 2], &["jp_anki".to_string()]), 2);
+    assert!(deck.tags.is_empty());
+    assert_eq!(deck.notes[1].tags, ["chapter_1"]);
+}
+
+#[tokio::test]
+async fn sub_decks() {
+    let dir = "tests/test-files";
+    let deck = Deck::from_json(r#"{ "id": 2, "name": "Mining", "notes": [
+        { "id": 1, "word": "a", "definition": "", "transcription": "", "subDeck": "Show A:: Ep 3" },
+        { "id": 2, "word": "b", "definition": "", "transcription": "" },
+        { "id": 3, "word": "c", "definition": "", "transcription": "", "subDeck": "Show A::Ep 3" }
+    ] }"#).unwrap();
+
+    assert_eq!(deck.sub_deck_name(&deck.notes[0]), "Mining::Show A::Ep 3");
+    assert_eq!(deck.sub_deck_name(&deck.notes[1]), "Mining");
+    assert_eq!(deck.sub_deck_id("Mining"), 2);
+    assert_ne!(deck.sub_deck_id("Mining::Show A::Ep 3"), deck.sub_deck_id("Mining::Show A::Ep 4"));
+
+    let ep3 = deck.sub_deck_id("Mining::Show A::Ep 3");
+    assert_eq!(deck.sub_decks(), [
+        SubDeck { id: 2, name: "Mining".to_string(), note_ids: vec![2] },
+        SubDeck { id: ep3, name: "Mining::Show A::Ep 3".to_string(), note_ids: vec![1, 3] },
+    ]);
+
+    let other = Deck::from_file(&format!("{}/deck.json", dir)).await.unwrap();
+    assert_eq!(other.sub_decks().len(), 1);
+    assert_eq!(other.sub_decks()[0].id, 1);
+    assert_eq!(other.sub_decks()[0].note_ids.len(), other.notes.len());
+
+    let first = Package::new(deck, Template::from_dir(dir).await.unwrap()).await.unwrap();
+    let second = Package::new(other, Template::from_dir(dir).await.unwrap()).await.unwrap();
+    let dest = format!("{}/multi.apkg", dir);
+    let media_dir = "tests/test-files/media";
+
+    write_packages(&[(&first, media_dir, None), (&second, media_dir, None)], &dest, &Monitor::silent())
+        .await
+        .unwrap();
+    let _ = std::fs::remove_file(&dest);
+
+    // Anki would merge both decks into one
+    let err = write_packages(&[(&second, media_dir, None), (&second, media_dir, None)], &dest, &Monitor::silent())
+        .await
+        .unwrap_err();
+    assert_matches!(err.downcast_ref::<DeckError>(), Some(DeckError::DuplicateDeckIdError(1, _, _)));
+
+    // Static files are added once if identical, different files with the same name are rejected
+    let (static_a, static_b) = ("tests/test-files/static-a", "tests/test-files/static-b");
+    for (static_dir, content) in [(static_a, "a"), (static_b, "a")] {
+        std::fs::create_dir_all(static_dir).unwrap();
+        std::fs::write(format!("{}/_font.ttf", static_dir), content).unwrap();
+    }
+    let packages = [(&first, media_dir, Some(static_a)), (&second, media_dir, Some(static_b))];
+    write_packages(&packages, &dest, &Monitor::silent()).await.unwrap();
+    let _ = std::fs::remove_file(&dest);
+
+    std::fs::write(format!("{}/_font.ttf", static_b), "b").unwrap();
+    let err = write_packages(&packages, &dest, &Monitor::silent()).await.unwrap_err();
+    assert_matches!(err.downcast_ref::<DeckError>(), Some(DeckError::MediaConflictError(_)));
+
+    std::fs::remove_dir_all(static_a).unwrap();
+    std::fs::remove_dir_all(static_b).unwrap();
+}
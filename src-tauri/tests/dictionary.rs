@@ -0,0 +1,31 @@
+use app::dictionary::Dictionary;
+use app::progress::Monitor;
+use std::fs;
+
+#[test]
+fn import_and_lookup() {
+    let path = "tests/test-files/dictionary-test.sqlite";
+    let _ = fs::remove_file(path);
+    let mut dict = Dictionary::open(path).unwrap();
+
+    let count = dict.import_file("tests/test-files/jmdict.xml", &Monitor::silent()).unwrap();
+    assert_eq!(count, 2);
+
+    let entries = dict.lookup("食べる").unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].readings[0].kana, "たべる");
+    assert_eq!(entries[0].senses[0].pos, ["v5r"]);
+    assert_eq!(entries[0].senses[0].glosses, ["to eat"]);
+    assert_eq!(entries[0].senses[1].pos, ["v5r"]);
+    assert_eq!(entries[0].senses[1].glosses, ["to live on (e.g. a salary) & survive"]);
+
+    let entries = dict.lookup("今日").unwrap();
+    let readings: Vec<&str> = entries[0].readings.iter().map(|it| it.kana.as_str()).collect();
+    assert_eq!(readings, ["きょう", "こんにち"]);
+
+    assert_eq!(dict.lookup("キョウ").unwrap().len(), 1);
+    assert!(dict.lookup("猫").unwrap().is_empty());
+
+    drop(dict);
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,24 @@
+use app::deck::Deck;
+use app::error::{CommandError, ErrorKind};
+use app::spreadsheet::{read_notes, MergeStrategy, TableOptions};
+
+#[tokio::test]
+async fn error_kinds() {
+    let err = CommandError::from(Deck::from_file("tests/test-files/none.json").await.err().unwrap());
+    assert_eq!(err.kind, ErrorKind::NotFound);
+    assert_eq!(err.path.as_deref(), Some("tests/test-files/none.json"));
+
+    let err = CommandError::from(Deck::from_json(r#"{ "id": 1, "name": "test", "notes": [
+        { "id": 3, "word": "a", "definition": "", "transcription": "" },
+        { "id": 3, "word": "b", "definition": "", "transcription": "" }
+    ] }"#).err().unwrap());
+    assert_eq!(err.kind, ErrorKind::InvalidDeck);
+    assert_eq!(err.note_id, Some(3));
+
+    let err = CommandError::from(Deck::from_json("{").err().unwrap());
+    assert_eq!(err.kind, ErrorKind::InvalidDeck);
+
+    let options = TableOptions { merge: MergeStrategy::ByWord, ..Default::default() };
+    let err = CommandError::from(read_notes(&mut Deck::default(), "definition\ncat\n".as_bytes(), b',', &options).err().unwrap());
+    assert_eq!(err.kind, ErrorKind::InvalidTable);
+}
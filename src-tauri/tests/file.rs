@@ -1,10 +1,15 @@
 use app::file::*;
 use std::path::Path;
 
-#[test]
-fn find_by_extension_test() {
-    let file = "tests/test-files/media/test";
-    let extensions = vec!["mp4"];
-    let ext = find_extension(file, &extensions).unwrap();
-    assert!(Path::new(&format!("{}.{}", file, ext)).exists());
+#[tokio::test]
+async fn write_atomic_test() {
+    let path = "tests/test-files/atomic.txt";
+
+    write_atomic(path, "first").await.unwrap();
+    write_atomic(path, "second").await.unwrap();
+
+    assert_eq!(std::fs::read_to_string(path).unwrap(), "second");
+    assert!(!Path::new(&format!("{}.tmp", path)).exists());
+
+    std::fs::remove_file(path).unwrap();
 }
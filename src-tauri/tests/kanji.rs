@@ -1,4 +1,4 @@
-use app::kanji::{generate_furigana, rubify};
+use app::kanji::{align_furigana, generate_furigana, parse_ruby, rubify, RubyStyle};
 
 #[test]
 fn test_generate_furigana() {
@@ -6,18 +6,18 @@ fn test_generate_furigana() {
         "侍は強かった",
         "さむらいはつよかった"
     ).unwrap();
-    assert_eq!("侍", pairs[0][0]);
-    assert_eq!("さむらい", pairs[0][1]);
-    assert_eq!("は", pairs[1][0]);
-    assert_eq!("", pairs[1][1]);
-    assert_eq!("強", pairs[2][0]);
-    assert_eq!("つよ", pairs[2][1]);
-    assert_eq!("かった", pairs[3][0]);
-    assert_eq!("", pairs[3][1]);
+    assert_eq!("侍", pairs[0].base);
+    assert_eq!(Some("さむらい"), pairs[0].reading.as_deref());
+    assert_eq!("は", pairs[1].base);
+    assert_eq!(None, pairs[1].reading);
+    assert_eq!("強", pairs[2].base);
+    assert_eq!(Some("つよ"), pairs[2].reading.as_deref());
+    assert_eq!("かった", pairs[3].base);
+    assert_eq!(None, pairs[3].reading);
 
     let pairs = generate_furigana("破壊", "はかい").unwrap();
-    assert_eq!("破壊", pairs[0][0]);
-    assert_eq!("はかい", pairs[0][1]);
+    assert_eq!("破壊", pairs[0].base);
+    assert_eq!(Some("はかい"), pairs[0].reading.as_deref());
 }
 
 #[test]
@@ -27,4 +27,41 @@ fn test_generate_ruby_string() {
         "きょうはほんとうにあついね"
     ).unwrap();
     assert_eq!("今日[きょう]は 本当[ほんとう]に 暑[あつ]いね", s);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_kanji_like_characters() {
+    assert_eq!("人々[ひとびと]", rubify("人々", "ひとびと").unwrap());
+    assert_eq!("一ヶ月[いっかげつ]", rubify("一ヶ月", "いっかげつ").unwrap());
+}
+
+#[test]
+fn test_ruby_styles() {
+    let segments = generate_furigana("お茶を飲む", "おちゃをのむ").unwrap();
+
+    assert_eq!("お 茶[ちゃ]を 飲[の]む", RubyStyle::Anki.render(&segments));
+    assert_eq!("お<ruby>茶<rt>ちゃ</rt></ruby>を<ruby>飲<rt>の</rt></ruby>む", RubyStyle::Html.render(&segments));
+    assert_eq!("お｜茶《ちゃ》を｜飲《の》む", RubyStyle::Aozora.render(&segments));
+    assert_eq!("おちゃをのむ", RubyStyle::Kana.render(&segments));
+    assert_eq!(segments, parse_ruby("お茶「ちゃ」を飲[の]む"));
+}
+
+#[test]
+fn test_normalized_alignment() {
+    assert_eq!("珈琲[コーヒー]", rubify("珈琲", "コーヒー").unwrap());
+    assert_eq!("ビール 瓶[びん]", rubify("ビール瓶", "びいるびん").unwrap());
+    assert_eq!("ガイド 本[ほん]", rubify("ｶﾞｲﾄﾞ本", "がいどほん").unwrap());
+
+    let (segments, warning) = align_furigana("強かった", "つよくない");
+    assert!(warning.is_some());
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].reading.as_deref(), Some("つよくない"));
+
+    let (segments, _) = align_furigana("ｶﾞｲﾄﾞ本", "ほん");
+    assert_eq!(segments[0].base, "ガイド本");
+
+    let (segments, warning) = align_furigana("侍", "");
+    assert!(warning.is_none());
+    assert_eq!(segments.len(), 1);
+    assert_eq!((segments[0].base.as_str(), segments[0].reading.as_deref()), ("侍", None));
+}
@@ -0,0 +1,27 @@
+use app::kanji::{generate_furigana, generate_furigana_with, RubyStyle};
+use app::kanjidic::KanjiReadings;
+
+#[test]
+fn read_kanjidic() {
+    let readings = KanjiReadings::from_file("tests/test-files/kanjidic2.xml").unwrap();
+
+    assert_eq!(readings.len(), 5);
+    assert_eq!(readings.get('野'), ["や", "の"]);
+    assert_eq!(readings.get('学'), ["がく", "まな"]);
+    assert!(readings.get('猫').is_empty());
+}
+
+#[test]
+fn dictionary_alignment() {
+    let readings = KanjiReadings::from_file("tests/test-files/kanjidic2.xml").unwrap();
+    let render = |word, reading| {
+        RubyStyle::Anki.render(&generate_furigana_with(word, reading, Some(&readings)).unwrap())
+    };
+
+    assert!(generate_furigana("人と", "ひとと").is_err());
+    assert_eq!("人[ひと]と", render("人と", "ひとと"));
+    assert_eq!("人々[ひとびと]", render("人々", "ひとびと"));
+    assert_eq!("学校[がっこう]", render("学校", "がっこう"));
+    // Kanji missing from the table fall back to the heuristic
+    assert_eq!("今日[きょう]は", render("今日は", "きょうは"));
+}
@@ -0,0 +1,38 @@
+use app::deck::{Deck, MediaNaming};
+use app::media::MediaIndex;
+use std::fs;
+
+#[test]
+fn media_index() {
+    let dir = "tests/test-files/media-index";
+    fs::create_dir_all(dir).unwrap();
+
+    for name in ["1_2.JPG", "1_2.mp4", "1_2r.mp3", "1_3.png", "1_9.png", "notes.txt"] {
+        fs::write(format!("{}/{}", dir, name), "").unwrap();
+    }
+
+    let media = MediaIndex::scan(dir).unwrap();
+
+    assert_eq!(media.media("1_2"), ["1_2.mp4", "1_2.JPG"]);
+    assert!(media.media("1_4").is_empty());
+    assert!(media.contains("1_2r.mp3"));
+    assert!(!media.contains("1_3r.mp3"));
+
+    let mut deck = Deck::from_json(r#"{ "id": 1, "name": "test", "notes": [
+        { "id": 2, "word": "a", "definition": "", "transcription": "" },
+        { "id": 3, "word": "b", "definition": "", "transcription": "" }
+    ] }"#).unwrap();
+    deck.media_naming = MediaNaming::Separated;
+
+    assert_eq!(deck.unused_media(&media), ["1_9.png", "notes.txt"]);
+
+    // Legacy decks name files after the word of the note
+    fs::write(format!("{}/a_r.mp3", dir), "").unwrap();
+    deck.media_naming = MediaNaming::Legacy;
+    let media = MediaIndex::scan(dir).unwrap();
+    assert!(!deck.unused_media(&media).contains(&"a_r.mp3"));
+    assert!(deck.unused_media(&media).contains(&"1_2.mp4"));
+
+    fs::remove_dir_all(dir).unwrap();
+    assert!(MediaIndex::scan(dir).unwrap().media("1_2").is_empty());
+}
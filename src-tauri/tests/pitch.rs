@@ -0,0 +1,40 @@
+use app::deck::{Deck, Note};
+use app::pitch::{fill_pitch, pitch_pattern, split_morae, PitchAccents, PitchStyle};
+
+#[test]
+fn read_accents() {
+    let accents = PitchAccents::from_file("tests/test-files/accents.txt").unwrap();
+
+    assert_eq!(accents.get("橋", "はし"), Some(&[2][..]));
+    assert_eq!(accents.get("今日", "コンニチ"), Some(&[1, 0][..]));
+    assert_eq!(accents.get("ある", "ある"), Some(&[1][..]));
+
+    let mut deck = Deck {
+        notes: vec![
+            Note { word: "飴".to_string(), reading: Some("あめ".to_string()), ..Default::default() },
+            Note { word: "橋[はし]".to_string(), ..Default::default() },
+            Note { word: "猫".to_string(), reading: Some("ねこ".to_string()), ..Default::default() },
+        ],
+        ..Default::default()
+    };
+
+    assert_eq!(fill_pitch(&mut deck, &accents), 2);
+    assert_eq!(deck.notes[0].pitch, Some(vec![0]));
+    assert_eq!(deck.notes[1].pitch, Some(vec![2]));
+    assert_eq!(deck.notes[2].pitch, None);
+}
+
+#[test]
+fn render_pitch() {
+    assert_eq!(split_morae("きょうしゃ"), ["きょ", "う", "しゃ"]);
+    assert_eq!(pitch_pattern(3, 0), [false, true, true, true]);
+    assert_eq!(pitch_pattern(3, 1), [true, false, false, false]);
+    assert_eq!(pitch_pattern(3, 2), [false, true, false, false]);
+
+    let html = PitchStyle::Html.render("はし", &[2]);
+    assert_eq!(html, "<span class=\"pitch\"><span class=\"pitch-low\">は</span><span class=\"pitch-high pitch-drop\">し</span></span>");
+
+    let svg = PitchStyle::Svg.render("はし", &[1, 0]);
+    assert_eq!(svg.matches("<svg").count(), 2);
+    assert_eq!(svg.matches("<circle").count(), 6);
+}
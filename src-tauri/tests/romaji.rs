@@ -0,0 +1,46 @@
+use app::deck::{Deck, Note};
+use app::ext::string::StringExt;
+use app::romaji::{kana_to_romaji, romaji_to_kana, Romanization};
+
+#[test]
+fn convert_romaji() {
+    assert_eq!("カタカナ", "かたかな".to_string().to_katakana());
+    assert_eq!("ひらがな", "ヒラガナ".to_string().to_hiragana());
+
+    assert_eq!("shinbun", kana_to_romaji("しんぶん", Romanization::Hepburn));
+    assert_eq!("tyotto", kana_to_romaji("ちょっと", Romanization::Kunrei));
+    assert_eq!("matcha", kana_to_romaji("まっちゃ", Romanization::Hepburn));
+    assert_eq!("kon'ya", kana_to_romaji("こんや", Romanization::Hepburn));
+    assert_eq!("koohii", kana_to_romaji("コーヒー", Romanization::Hepburn));
+    assert_eq!("fairu", kana_to_romaji("ファイル", Romanization::Hepburn));
+    assert_eq!("sheru", kana_to_romaji("シェル", Romanization::Hepburn));
+
+    assert_eq!("こんにちわ", romaji_to_kana("konnichiwa"));
+    assert_eq!("ちょっと", romaji_to_kana("tyotto"));
+    assert_eq!("まっちゃ", romaji_to_kana("matcha"));
+    assert_eq!("しんぶん", romaji_to_kana("shinbun"));
+    assert_eq!("こんや", romaji_to_kana("kon'ya"));
+    assert_eq!("ふぁいる", romaji_to_kana("fairu"));
+}
+
+#[test]
+fn search_notes() {
+    let note = |word: &str, reading: Option<&str>, definition: &str| Note {
+        word: word.to_string(),
+        reading: reading.map(|it| it.to_string()),
+        definition: definition.to_string(),
+        ..Default::default()
+    };
+    let deck = Deck {
+        notes: vec![note("雨", Some("あめ"), "rain"), note("コーヒー", None, "coffee"), note("侍[さむらい]", None, "samurai")],
+        ..Default::default()
+    };
+
+    let words = |query| deck.search(query).iter().map(|it| it.word.clone()).collect::<Vec<String>>();
+
+    assert_eq!(words("ame"), ["雨"]);
+    assert_eq!(words("koohii"), ["コーヒー"]);
+    assert_eq!(words("samu"), ["侍[さむらい]"]);
+    assert_eq!(words("Rain"), ["雨"]);
+    assert!(words("neko").is_empty());
+}
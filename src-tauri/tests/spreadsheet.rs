@@ -0,0 +1,89 @@
+use app::deck::{Deck, Note};
+use app::spreadsheet::{export_notes, read_notes, write_notes, MergeStrategy, TableOptions};
+
+fn note(id: u32, word: &str, reading: Option<&str>, definition: &str) -> Note {
+    Note {
+        id: Some(id),
+        word: word.to_string(),
+        reading: reading.map(|it| it.to_string()),
+        definition: definition.to_string(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn round_trip() {
+    let deck = Deck {
+        notes: vec![note(1, "侍", Some("さむらい"), "samurai, \"warrior\""), note(2, "です", None, "to be")],
+        ..Default::default()
+    };
+    let options = TableOptions::default();
+
+    let mut buf = Vec::new();
+    write_notes(&deck, &mut buf, b'\t', &options).unwrap();
+
+    let mut copy = Deck::default();
+    let report = read_notes(&mut copy, buf.as_slice(), b'\t', &options).unwrap();
+
+    assert_eq!(report.added, 2);
+    assert!(report.failed.is_empty());
+    assert_eq!(copy.notes[0].id, Some(1));
+    assert_eq!(copy.notes[0].definition, "samurai, \"warrior\"");
+    assert_eq!(copy.notes[1].reading, None);
+}
+
+#[test]
+fn round_trip_extra_columns() {
+    let mut samurai = note(1, "侍", Some("さむらい"), "samurai");
+    samurai.sub_deck = Some("Show A::Ep 3".to_string());
+    samurai.pitch = Some(vec![0, 2]);
+    samurai.fields.insert("Sentence".to_string(), "侍だ".to_string());
+    let deck = Deck { fields: vec!["Sentence".to_string()], notes: vec![samurai], ..Default::default() };
+    let options = TableOptions::for_deck(&deck);
+
+    let mut buf = Vec::new();
+    write_notes(&deck, &mut buf, b',', &options).unwrap();
+    assert!(String::from_utf8_lossy(&buf).starts_with("id,word,reading,definition,transcription,useReading,tags,subDeck,pitch,Sentence\n"));
+
+    let mut copy = Deck { fields: deck.fields.clone(), ..Default::default() };
+    read_notes(&mut copy, buf.as_slice(), b',', &options).unwrap();
+
+    assert_eq!(copy.notes[0].sub_deck.as_deref(), Some("Show A::Ep 3"));
+    assert_eq!(copy.notes[0].pitch, Some(vec![0, 2]));
+    assert_eq!(copy.notes[0].fields["Sentence"], "侍だ");
+}
+
+#[test]
+fn merge_rows() {
+    let mut deck = Deck {
+        notes: vec![note(1, "侍", Some("さむらい"), "samurai")],
+        ..Default::default()
+    };
+    let csv = "word,definition\n侍,warrior\n猫,cat\n,no word\n";
+    let options = TableOptions { merge: MergeStrategy::ByWord, ..Default::default() };
+
+    let report = read_notes(&mut deck, csv.as_bytes(), b',', &options).unwrap();
+
+    assert_eq!(report.updated, 1);
+    assert_eq!(report.added, 1);
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].line, 4);
+    assert_eq!(deck.notes[0].definition, "warrior");
+    assert_eq!(deck.notes[0].reading.as_deref(), Some("さむらい"));
+    assert_eq!(deck.notes[1].id, Some(2));
+}
+
+#[tokio::test]
+async fn multiline_rows() {
+    let mut deck = Deck::default();
+    let csv = "word,definition\n侍,\"samurai\nwarrior\"\n,no word\n";
+    let options = TableOptions { merge: MergeStrategy::ByWord, ..Default::default() };
+
+    let report = read_notes(&mut deck, csv.as_bytes(), b',', &options).unwrap();
+
+    assert_eq!(report.added, 1);
+    assert_eq!(report.failed[0].line, 4);
+
+    let options = TableOptions { delimiter: Some('、'), ..Default::default() };
+    assert!(export_notes(&deck, "tests/test-files/notes.csv", &options).await.is_err());
+}
@@ -32,4 +32,18 @@ fn test_format_ruby() {
     assert_eq!(s, s.format_ruby());
     let s = "頭「あたま」がいい".to_string();
     assert_eq!("頭[あたま]がいい", s.format_ruby());
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_ruby_reading() {
+    let s = "今日[きょう]は 本当[ほんとう]に 暑[あつ]いね".to_string();
+    assert_eq!("きょうはほんとうにあついね", s.ruby_reading());
+    let s = "女「おんな」の子「こ」".to_string();
+    assert_eq!("おんなのこ", s.ruby_reading());
+}
+
+#[test]
+fn test_normalize_width() {
+    assert_eq!("ガッコウ ABC", "ｶﾞｯｺｳ ＡＢＣ".to_string().normalize_width());
+    assert_eq!("パン", "ﾊﾟﾝ".to_string().normalize_width());
+}
@@ -0,0 +1,67 @@
+use app::deck::{CardTemplate, Deck, Note, Package, Template};
+use app::template::{check_tags, parse_tags, render, ruby_filter, TagKind};
+use std::collections::HashMap;
+
+#[test]
+fn read_tags() {
+    let (tags, problems) = parse_tags("{{Word}}\n{{#Reading}}{{furigana: Reading}}{{/Reading}}{{! note }}\n{{Definition");
+
+    let kinds: Vec<(TagKind, &str, usize)> = tags.iter().map(|it| (it.kind, it.field.as_str(), it.line)).collect();
+    assert_eq!(kinds, [
+        (TagKind::Field, "Word", 1),
+        (TagKind::Section, "Reading", 2),
+        (TagKind::Field, "Reading", 2),
+        (TagKind::End, "Reading", 2),
+    ]);
+    assert_eq!(problems, [(3, "Unclosed tag".to_string())]);
+}
+
+#[test]
+fn check_template_tags() {
+    let fields = vec!["Word".to_string(), "Reading".to_string()];
+
+    assert!(check_tags("{{Word}}<hr>{{FrontSide}}{{^Reading}}{{Word}}{{/Reading}}", &fields).is_empty());
+
+    let problems = check_tags("{{#Word}}\n{{#Reading}}\n{{/Word}}\n{{Notes}}\n{{/Reading}}", &fields);
+    assert_eq!(problems, [
+        (2, "Section Reading is not closed".to_string()),
+        (4, "Unknown field Notes".to_string()),
+        (5, "/Reading closes no section".to_string()),
+    ]);
+}
+
+#[tokio::test]
+async fn render_card() {
+    assert_eq!(ruby_filter("furigana", "読[よ]む 本[ほん]"), "<ruby><rb>読</rb><rt>よ</rt></ruby>む<ruby><rb>本</rb><rt>ほん</rt></ruby>");
+    assert_eq!(ruby_filter("kana", "読[よ]む 本[ほん]"), "よむほん");
+    assert_eq!(ruby_filter("kanji", "読[よ]む [sound:a.mp3]"), "読む [sound:a.mp3]");
+
+    let fields: HashMap<String, String> = [("Word", "<b>本</b>"), ("Reading", ""), ("Media", "[sound:1_2.mp4]")]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    assert_eq!(
+        render("{{text:Word}}{{#Reading}}R{{/Reading}}{{^Reading}}-{{/Reading}}{{Media}}", &fields),
+        "本-<video controls src=\"1_2.mp4\"></video>",
+    );
+
+    let deck = Deck { id: 1, name: "test".to_string(), ..Default::default() };
+    let template = Template {
+        cards: vec![CardTemplate {
+            name: "Default".to_string(),
+            front: "{{Word}}".to_string(),
+            back: "{{FrontSide}}<hr>{{furigana:Reading}}".to_string(),
+        }],
+        css: String::new(),
+    };
+    let note = Note {
+        id: Some(3),
+        word: "本".to_string(),
+        reading: Some("ほん".to_string()),
+        ..Default::default()
+    };
+    let preview = Package::new(deck, template).await.unwrap().preview(&note, None, "media").await.unwrap();
+
+    assert_eq!(preview.front, "本");
+    assert_eq!(preview.back, "本<hr><ruby><rb>本</rb><rt>ほん</rt></ruby>");
+}
@@ -1,20 +1,33 @@
+import { invoke } from '@tauri-apps/api'
 import { bind } from 'svelte-simple-modal'
 import { writable } from 'svelte/store'
+import type { Backup } from './models'
+import Backups from './component/modal/Backups.svelte'
 import Confirm from './component/modal/Confirm.svelte'
 import Error from './component/modal/Error.svelte'
 import Loading from './component/modal/Loading.svelte'
 import NewDeck from './component/modal/NewDeck.svelte'
+import Progress from './component/modal/Progress.svelte'
 
 export const errorModal = writable(null)
 export const confirmModal = writable(null)
 export const loadingModal = writable(null)
 export const newDeckModal = writable(null)
+export const progressModal = writable(null)
+export const backupsModal = writable(null)
+
+let taskCount = 0
 
 export function showErrorModal(msg?: string, desc?: any) {
     if (desc) console.error(desc)
     errorModal.set(bind(Error, { msg, desc }))
 }
 
+/** Error modal of a command run with `invokeWithProgress`, cancellation included */
+export function showTaskErrorModal(err: any) {
+    showErrorModal(err?.kind === 'cancelled' ? 'Cancelled' : null, err)
+}
+
 export function showConfirmModal(msg: string, confirm: () => void, cancel?: () => void) {
     confirmModal.set(bind(Confirm, { msg, handler: (ok: boolean) => { if (ok) confirm(); else cancel?.call(null) } }))
 }
@@ -31,10 +44,28 @@ export function showNewDeckModal(): Promise<boolean> {
     })
 }
 
+/** Lets the user pick one of `backups`, resolving to its name or `null` when cancelled */
+export function showBackupsModal(backups: Backup[]): Promise<string> {
+    return new Promise<string>(resolve => {
+        backupsModal.set(bind(Backups, { backups, handler: (name: string) => resolve(name) }))
+    })
+}
+
 export function showLoadingModal(msg?: string) {
     loadingModal.set(bind(Loading, { msg }))
 }
 
 export function hideLoadingModal() {
     loadingModal.set(null)
+}
+
+/** Runs a cancellable command, showing its progress events with a Cancel button */
+export async function invokeWithProgress<T>(msg: string, command: string, args: Record<string, unknown>): Promise<T> {
+    const taskId = `${command}-${++taskCount}`
+    progressModal.set(bind(Progress, { msg, taskId }))
+    try {
+        return await invoke<T>(command, { ...args, taskId })
+    } finally {
+        progressModal.set(null)
+    }
 }
\ No newline at end of file
@@ -6,7 +6,11 @@ export class Note {
         public transcription?: string,
         public useReading: boolean = false,
         public audioState?: string,
-        public id?: number
+        public id?: number,
+        public pitch?: number[],
+        public fields?: Record<string, string>,
+        public tags: string[] = [],
+        public subDeck?: string
     ) { }
 }
 
@@ -14,12 +18,74 @@ export class Deck {
     constructor(
         public name?: string,
         public id?: string,
-        public notes: Note[] = []
+        public notes: Note[] = [],
+        public mediaNaming: string = 'separated',
+        public rubyStyle: string = 'anki',
+        public pitchStyle: string = 'svg',
+        public romaji?: string,
+        public fields: string[] = [],
+        public tags: string[] = [],
+        public modelId?: number,
+        public cards: string[] = []
     ) { }
 }
 
 export class Settings {
     constructor(
         public defaultDir?: string,
+        public kanjidicPath?: string,
+        public backupCount?: number,
     ) { }
-}
\ No newline at end of file
+}
+
+export interface Backup {
+    name: string
+    timestamp: number
+}
+
+export interface CommandError {
+    kind: string
+    path?: string
+    noteId?: number
+    message: string
+}
+
+export interface NotesImport {
+    json: string
+    report: {
+        added: number
+        updated: number
+        failed: { line: number, message: string }[]
+    }
+}
+
+export interface DictionaryEntry {
+    id: number
+    kanji: string[]
+    readings: { kana: string, restrictions?: string[], noKanji?: boolean }[]
+    senses: { pos: string[], glosses: string[] }[]
+}
+
+export interface TemplateProblem {
+    card: string
+    side: string
+    line: number
+    message: string
+}
+
+export interface ProgressEvent {
+    taskId?: string
+    task: string
+    progress: {
+        done: number
+        total: number
+        current?: string
+        failures: number
+    }
+}
+
+export interface CardPreview {
+    front: string
+    back: string
+    css: string
+}
@@ -25,11 +25,15 @@ deck.subscribe(deck => {
 export async function loadSettings() {
     let config: Settings = JSON.parse(await invoke('read_settings'))
     setSetting('defaultDir', config.defaultDir)
+    setSetting('kanjidicPath', config.kanjidicPath)
+    setSetting('backupCount', config.backupCount ?? '')
 }
 
 export async function saveSettings() {
     let config = new Settings(
-        settingsValue('defaultDir')
+        settingsValue('defaultDir'),
+        settingsValue('kanjidicPath'),
+        backupCount(),
     )
 
     await invoke('write_settings', { json: JSON.stringify(config) })
@@ -47,6 +51,16 @@ export function setSetting(name: string, value: any) {
     setField(settingsForm, name, value)
 }
 
+/** Number of backups kept per deck, the backend default when not set */
+export function backupCount(): number {
+    const count = Number(settingsValue('backupCount'))
+    return Number.isInteger(count) && count > 0 ? count : undefined
+}
+
+export async function writeBackup() {
+    await invoke('write_backup', { dir: deckPath(), json: jsonDeck(), keep: backupCount() ?? null })
+}
+
 export function deckPath(): string {
     return get(deckPathField).value
 }
@@ -113,7 +127,9 @@ export function addNote(note: Note) {
 
 function createSettingsForm(): Form {
     return form(
-        field('defaultDir', '')
+        field('defaultDir', ''),
+        field('kanjidicPath', ''),
+        field('backupCount', '')
     )
 }
 
@@ -124,7 +140,9 @@ function createNoteForm(): Form {
         field('useReading', false),
         field('definition', '', [required()]),
         field('media', ''),
-        field('transcription', '')
+        field('transcription', ''),
+        field('subDeck', ''),
+        field('tags', '')
     )
 }
 